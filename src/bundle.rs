@@ -0,0 +1,321 @@
+/*
+* mkdf — password-based master key derivation and verification tool
+* Copyright (C) 2026 L. M. Oukaci
+*
+* Contact: ouka.lotfi@gmail.com
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Binary bundle format.
+//!
+//! A bundle is the magic `MKDF`, a version byte (currently 1), then a sequence of records until
+//! the end of the data. Each record is a tag byte, the length of its value as a big-endian u16,
+//! then the value itself:
+//!
+//! | tag | field      | value                                                               |
+//! |-----|------------|---------------------------------------------------------------------|
+//! | 1   | MK params  | mode (u32), N (u64), r (u32), p (u32), t (u32), all big-endian      |
+//! | 2   | DPK params | same as the MK params                                               |
+//! | 3   | salt 1     | 16 raw bytes                                                        |
+//! | 4   | MK         | raw yescrypt output                                                 |
+//! | 5   | phash      | raw yescrypt output (the hash only, not the PHC string)             |
+//! | 6   | salt 2     | 16 raw bytes                                                        |
+//! | 7   | DPK        | raw yescrypt output                                                 |
+//! | 8   | salt 3     | 16 raw bytes                                                        |
+//! | 9   | DPK source | 0 if the DPK is derived from the password, 1 if from the MK         |
+//! | 10  | signature  | Ed25519 signature over the encoding of the other records            |
+//! | 11  | digest     | how the phash was made: 1 HMAC-SHA-256, 2 HMAC-SHA-512, 3 BLAKE3    |
+//! | 12  | metadata   | format version (u8), creation time (u64 Unix seconds), MKDF version |
+//! | 13  | phash 2    | digest (u8, 0 for yescrypt, else as tag 11), then the raw hash      |
+//!
+//! Each tag appears at most once; the params and salt 1 are mandatory while the other records are
+//! only present for the stages that were computed. Bundles without a DPK source predate it and
+//! derive the DPK from the MK. The digest is only recorded when it isn't yescrypt, so that bundles
+//! made with the default can still be read by versions that predate it. A second phash, made with
+//! another digest, lets verification accept either during a migration. The metadata is optional
+//! and never holds anything secret; its time is big-endian and the MKDF version is UTF-8. The
+//! signature is always the last record, and covers the bundle as re-encoded without it, so that it
+//! doesn't depend on the order the records were read in.
+
+use crate::{DigestAlgo, DpkSource, StageParams, SALT_LEN};
+use yescrypt::Mode;
+
+const MAGIC: &[u8; 4] = b"MKDF";
+pub const VERSION: u8 = 1;
+
+const TAG_MK_PARAMS: u8 = 1;
+const TAG_DPK_PARAMS: u8 = 2;
+const TAG_SALT1: u8 = 3;
+const TAG_MK: u8 = 4;
+const TAG_PHASH: u8 = 5;
+const TAG_SALT2: u8 = 6;
+const TAG_DPK: u8 = 7;
+const TAG_SALT3: u8 = 8;
+const TAG_DPK_SOURCE: u8 = 9;
+const TAG_SIGNATURE: u8 = 10;
+const TAG_DIGEST: u8 = 11;
+const TAG_METADATA: u8 = 12;
+const TAG_PHASH2: u8 = 13;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bundle {
+    pub mk_params: StageParams,
+    pub dpk_params: StageParams,
+    pub salt1: Vec<u8>,
+    pub mk: Option<Vec<u8>>,
+    pub phash: Option<Vec<u8>>,
+    pub phash2: Option<(DigestAlgo, Vec<u8>)>,
+    pub salt2: Option<Vec<u8>>,
+    pub dpk: Option<Vec<u8>>,
+    pub salt3: Option<Vec<u8>>,
+    pub dpk_source: DpkSource,
+    pub digest: DigestAlgo,
+    pub metadata: Option<Metadata>,
+    pub signature: Option<Vec<u8>>,
+}
+
+impl Bundle {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        push_record(&mut out, TAG_MK_PARAMS, &encode_params(&self.mk_params));
+        push_record(&mut out, TAG_DPK_PARAMS, &encode_params(&self.dpk_params));
+        push_record(&mut out, TAG_SALT1, &self.salt1);
+        let dpk_source = match self.dpk_source {
+            DpkSource::Password => 0,
+            DpkSource::Mk => 1,
+        };
+        push_record(&mut out, TAG_DPK_SOURCE, &[dpk_source]);
+        if self.digest != DigestAlgo::Yescrypt {
+            push_record(&mut out, TAG_DIGEST, &[digest_id(self.digest)]);
+        }
+        if let Some(metadata) = &self.metadata {
+            push_record(&mut out, TAG_METADATA, &metadata.encode());
+        }
+        let optional = [
+            (TAG_MK, &self.mk),
+            (TAG_PHASH, &self.phash),
+            (TAG_SALT2, &self.salt2),
+            (TAG_DPK, &self.dpk),
+            (TAG_SALT3, &self.salt3),
+        ];
+        for (tag, value) in optional {
+            if let Some(value) = value {
+                push_record(&mut out, tag, value);
+            }
+        }
+        if let Some((digest, phash)) = &self.phash2 {
+            push_record(
+                &mut out,
+                TAG_PHASH2,
+                &[&[digest_id(*digest)], &phash[..]].concat(),
+            );
+        }
+        if let Some(signature) = &self.signature {
+            push_record(&mut out, TAG_SIGNATURE, signature);
+        }
+
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Bundle, String> {
+        let version = version(data)?;
+        let mut rest = &data[MAGIC.len() + 1..];
+        if version != VERSION {
+            return Err(format!("unsupported bundle version {version}"));
+        }
+
+        let mut records: [Option<&[u8]>; 14] = [None; 14];
+        while !rest.is_empty() {
+            if rest.len() < 3 {
+                return Err("truncated bundle".to_string());
+            }
+            let tag = rest[0];
+            let len = usize::from(u16::from_be_bytes([rest[1], rest[2]]));
+            let value = rest.get(3..3 + len).ok_or("truncated bundle")?;
+            let slot = records
+                .get_mut(usize::from(tag))
+                .filter(|_| tag != 0)
+                .ok_or(format!("unknown record tag {tag}"))?;
+            if slot.replace(value).is_some() {
+                return Err(format!("duplicate record tag {tag}"));
+            }
+            rest = &rest[3 + len..];
+            if tag == TAG_SIGNATURE && !rest.is_empty() {
+                return Err("the signature must be the last record".to_string());
+            }
+        }
+
+        for tag in [TAG_SALT1, TAG_SALT2, TAG_SALT3] {
+            if records[usize::from(tag)].is_some_and(|salt| salt.len() != SALT_LEN) {
+                return Err(format!("salts must be {SALT_LEN} bytes long"));
+            }
+        }
+
+        let mandatory =
+            |tag: u8, name: &str| records[usize::from(tag)].ok_or(format!("bundle has no {name}"));
+        let optional = |tag: u8| records[usize::from(tag)].map(<[u8]>::to_vec);
+        let dpk_source = match records[usize::from(TAG_DPK_SOURCE)] {
+            None | Some([1]) => DpkSource::Mk,
+            Some([0]) => DpkSource::Password,
+            Some(_) => return Err("malformed DPK source record".to_string()),
+        };
+        let digest = match records[usize::from(TAG_DIGEST)] {
+            None => DigestAlgo::Yescrypt,
+            Some(&[id]) if id != 0 => digest_from_id(id).ok_or("malformed digest record")?,
+            Some(_) => return Err("malformed digest record".to_string()),
+        };
+        let phash2 = match records[usize::from(TAG_PHASH2)] {
+            None => None,
+            Some(value) => {
+                let (&id, phash) = value.split_first().ok_or("malformed phash 2 record")?;
+                let digest = digest_from_id(id).ok_or("malformed phash 2 record")?;
+                Some((digest, phash.to_vec()))
+            }
+        };
+        Ok(Bundle {
+            mk_params: decode_params(mandatory(TAG_MK_PARAMS, "MK params")?)?,
+            dpk_params: decode_params(mandatory(TAG_DPK_PARAMS, "DPK params")?)?,
+            salt1: mandatory(TAG_SALT1, "salt 1")?.to_vec(),
+            mk: optional(TAG_MK),
+            phash: optional(TAG_PHASH),
+            phash2,
+            salt2: optional(TAG_SALT2),
+            dpk: optional(TAG_DPK),
+            salt3: optional(TAG_SALT3),
+            dpk_source,
+            digest,
+            metadata: records[usize::from(TAG_METADATA)]
+                .map(Metadata::decode)
+                .transpose()?,
+            signature: optional(TAG_SIGNATURE),
+        })
+    }
+
+    /// The bytes the signature covers: the bundle encoded without its signature
+    #[cfg(feature = "sign")]
+    pub fn signed_data(&self) -> Vec<u8> {
+        Bundle {
+            signature: None,
+            ..self.clone()
+        }
+        .encode()
+    }
+}
+
+/// The format version of an encoded bundle, read without decoding its records
+pub fn version(data: &[u8]) -> Result<u8, String> {
+    let rest = data
+        .strip_prefix(MAGIC)
+        .ok_or("not an MKDF bundle (bad magic)")?;
+
+    rest.first().copied().ok_or("truncated bundle".to_string())
+}
+
+/// What made a bundle and when, to re-derive it with the right tool years later
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub format_version: u8,
+    /// Unix time in seconds
+    pub created: u64,
+    pub tool_version: String,
+}
+
+impl Metadata {
+    pub fn now() -> Metadata {
+        let created = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+
+        Metadata {
+            format_version: VERSION,
+            created,
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![self.format_version];
+        out.extend_from_slice(&self.created.to_be_bytes());
+        out.extend_from_slice(self.tool_version.as_bytes());
+
+        out
+    }
+
+    fn decode(value: &[u8]) -> Result<Metadata, String> {
+        let malformed = || "malformed metadata record".to_string();
+        let (&format_version, rest) = value.split_first().ok_or_else(malformed)?;
+        let (created, tool_version) = rest.split_first_chunk::<8>().ok_or_else(malformed)?;
+
+        Ok(Metadata {
+            format_version,
+            created: u64::from_be_bytes(*created),
+            tool_version: String::from_utf8(tool_version.to_vec()).map_err(|_| malformed())?,
+        })
+    }
+}
+
+fn digest_id(digest: DigestAlgo) -> u8 {
+    match digest {
+        DigestAlgo::Yescrypt => 0,
+        DigestAlgo::Sha256 => 1,
+        DigestAlgo::Sha512 => 2,
+        DigestAlgo::Blake3 => 3,
+    }
+}
+
+fn digest_from_id(id: u8) -> Option<DigestAlgo> {
+    match id {
+        0 => Some(DigestAlgo::Yescrypt),
+        1 => Some(DigestAlgo::Sha256),
+        2 => Some(DigestAlgo::Sha512),
+        3 => Some(DigestAlgo::Blake3),
+        _ => None,
+    }
+}
+
+fn push_record(out: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    let len = u16::try_from(value.len()).expect("bundle records are short");
+    out.push(tag);
+    out.extend_from_slice(&len.to_be_bytes());
+    out.extend_from_slice(value);
+}
+
+fn encode_params(params: &StageParams) -> Vec<u8> {
+    let mut out = Vec::with_capacity(24);
+    out.extend_from_slice(&u32::from(params.mode).to_be_bytes());
+    out.extend_from_slice(&params.n.to_be_bytes());
+    out.extend_from_slice(&params.r.to_be_bytes());
+    out.extend_from_slice(&params.p.to_be_bytes());
+    out.extend_from_slice(&params.t.to_be_bytes());
+
+    out
+}
+
+fn decode_params(value: &[u8]) -> Result<StageParams, String> {
+    if value.len() != 24 {
+        return Err("malformed params record".to_string());
+    }
+    let u32_at = |i: usize| u32::from_be_bytes(value[i..i + 4].try_into().unwrap());
+    let mode = Mode::try_from(u32_at(0)).map_err(|_| "unknown yescrypt mode".to_string())?;
+
+    Ok(StageParams {
+        mode,
+        n: u64::from_be_bytes(value[4..12].try_into().unwrap()),
+        r: u32_at(12),
+        p: u32_at(16),
+        t: u32_at(20),
+    })
+}
@@ -17,14 +17,108 @@
 * You should have received a copy of the GNU General Public License
 * along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
-use clap::Parser;
+mod bundle;
+#[cfg(all(target_os = "linux", feature = "server"))]
+mod server;
+#[cfg(feature = "tui")]
+mod tui;
+
+use base64ct::{Base64, Base64ShaCrypt, Base64UrlUnpadded, Encoding};
+use bundle::{Bundle, Metadata};
+use clap::{ArgGroup, Parser, Subcommand, ValueEnum};
+use hmac::{digest::KeyInit, Hmac, Mac};
 use rand::{rngs::OsRng, TryRngCore}; // needed for salt
-use rayon::join;
-use std::io::{self, Read};
-use yescrypt::{CustomizedPasswordHasher, Mode, Yescrypt};
+use sha2::{Digest, Sha256, Sha512};
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use yescrypt::{CustomizedPasswordHasher, Mode, Params, PasswordHashRef, Yescrypt};
+use zeroize::{Zeroize, Zeroizing};
+
+// Security floor for the yescrypt params; anything below requires --force
+const MIN_N: u64 = 1024;
+const MIN_R: u32 = 8;
+const MIN_P: u32 = 1;
+// Soft floor on the memory used by the DPK stage; below it we only warn. yescrypt uses 128 * N * r * p
+// bytes, so the default DPK params (N = 32768, r = 32) use 128 MiB
+const SOFT_MIN_DPK_MEMORY: u64 = 64 << 20;
+// Salts are always 16 bytes long, which is also the floor (NIST SP 800-132 asks for 128 bits)
+const SALT_LEN: usize = 16;
+// Length of the yescrypt outputs (the MK, its hash and the DPK)
+const HASH_LEN: usize = 32;
+// Version of the JSON objects printed under --json, including errors and warnings; bumped whenever
+// a field is removed or changes meaning, but not when one is added
+const JSON_FORMAT_VERSION: u32 = 1;
+
+// Exit codes; these are a stable contract for scripts, listed by the hidden --print-exit-codes
+const EXIT_OK: i32 = 0;
+// The password doesn't match (or the bundles differ for diff)
+const EXIT_MISMATCH: i32 = 1;
+// I/O errors; clap also uses 2 for malformed command lines
+const EXIT_IO: i32 = 2;
+// Usage errors caught after parsing (e.g. bad params or salts)
+const EXIT_USAGE: i32 = 64;
+// A verification rejected because the phash's params are below --min-params, or the bundle format
+// is older than --min-format-version
+const EXIT_DOWNGRADE: i32 = 65;
+// A verification rejected because the bundle's signature is missing or invalid
+const EXIT_BAD_SIGNATURE: i32 = 66;
+// A hash output that doesn't verify under --verify-after, which is a bug in MKDF
+const EXIT_SOFTWARE: i32 = 70;
+// A run aborted by --timeout-ms, the same as timeout(1)
+const EXIT_TIMEOUT: i32 = 124;
+
+const EXIT_CODES: [(i32, &str); 8] = [
+    (EXIT_OK, "success; for verification, the password matches"),
+    (
+        EXIT_MISMATCH,
+        "the password doesn't match, the bundles differ (diff), or timing-test found a gap",
+    ),
+    (EXIT_IO, "I/O error, or malformed command line"),
+    (
+        EXIT_USAGE,
+        "invalid input (params, salts, phash, bundle, ...)",
+    ),
+    (
+        EXIT_DOWNGRADE,
+        "the phash params are below --min-params, or the bundle format below --min-format-version",
+    ),
+    (
+        EXIT_BAD_SIGNATURE,
+        "the bundle signature is missing or invalid",
+    ),
+    (
+        EXIT_SOFTWARE,
+        "internal error: the hash output failed --verify-after, or yescrypt failed",
+    ),
+    (EXIT_TIMEOUT, "the derivation exceeded --timeout-ms"),
+];
+
+// Set once from --verbose; read from wherever there is something worth reporting
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+// Set once from --json; errors and warnings are then reported as JSON objects on STDERR
+static JSON: AtomicBool = AtomicBool::new(false);
+// Set once from the thread count; the stages then run serially without going through rayon
+static SERIAL: AtomicBool = AtomicBool::new(false);
+// Set once from --json-pretty
+static JSON_PRETTY: AtomicBool = AtomicBool::new(false);
+// Set once from --no-trailing-newline
+static NO_TRAILING_NEWLINE: AtomicBool = AtomicBool::new(false);
+// Set by the first failure, so that a second one on another thread isn't reported too
+static FAILED: AtomicBool = AtomicBool::new(false);
+// Set once from --timeout-ms, along with the timeout itself for the error message
+static DEADLINE: OnceLock<(Instant, Duration)> = OnceLock::new();
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
+#[command(group(
+    ArgGroup::new("password_source")
+        .args(["password", "input_files", "prompt", "password_file", "password_fd"])
+))]
 struct Args {
     /// Hash the password read from STDIN
     #[arg(long = "hash", conflicts_with = "verify")]
@@ -35,148 +129,3430 @@ struct Args {
     verify: bool,
 
     /// Salt 1 (to hash the password and generate the MK works with verification only)
-    #[arg(long, requires = "verify")]
+    #[arg(long, requires = "verify", value_parser = parse_salt_arg)]
     s1: Option<String>,
 
     /// Salt 2 (to hash the MK and generate the MK's digest work with verification only)
-    #[arg(long, requires = "verify")]
+    #[arg(long, requires = "verify", value_parser = parse_salt_arg)]
     s2: Option<String>,
 
     /// Salt 3 (to hash the MK and generate the DPK works with verification only)
-    #[arg(long, requires = "verify")]
+    #[arg(long, requires = "verify", value_parser = parse_salt_arg)]
     s3: Option<String>,
 
     /// Password's hash (actually the MK's hash)
     #[arg(long, requires = "verify")]
     phash: Option<String>,
+
+    /// A second phash, made with --also-digest; the password is accepted if it matches either
+    #[arg(long, value_name = "HASH", requires_all = ["verify", "also_digest"], conflicts_with_all = ["bundle", "bundle_dir", "prehash_only", "dpk_fingerprint"])]
+    also_phash: Option<String>,
+
+    /// Read the phash from STDIN; the password must then come from another source
+    #[arg(long, requires_all = ["verify", "password_source"], conflicts_with_all = ["phash", "bundle"])]
+    phash_stdin: bool,
+
+    /// Comma-separated stages to compute and print in hash mode (default: digest,dpk); the MK is
+    /// always computed since the other stages are derived from it
+    #[arg(long, value_delimiter = ',', global = true)]
+    stages: Vec<Stage>,
+
+    /// Encoding of the salts, both printed in hash mode and passed on verify
+    #[arg(long, value_enum, default_value_t = SaltEncoding::Hex, global = true)]
+    salt_encoding: SaltEncoding,
+
+    /// Print the salts in groups of this many characters separated by spaces, for reading them
+    /// aloud; verify ignores the spaces
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..), global = true)]
+    salt_group: Option<u16>,
+
+    /// Letter case of the hex salts printed in hash mode; verify accepts either case
+    #[arg(long, value_enum, default_value_t = HexCase::Lower, global = true)]
+    hex_case: HexCase,
+
+    /// yescrypt N (CPU/memory cost) used to derive the MK and hash it
+    #[arg(long, default_value_t = 2048, global = true)]
+    mk_n: u64,
+
+    /// yescrypt r (block size) used to derive the MK and hash it
+    #[arg(long, default_value_t = 8, global = true)]
+    mk_r: u32,
+
+    /// yescrypt p (parallelism) used to derive the MK and hash it
+    #[arg(long, default_value_t = 1, global = true)]
+    mk_p: u32,
+
+    /// yescrypt N (CPU/memory cost) used to derive the DPK
+    #[arg(long, default_value_t = 32768, global = true)]
+    dpk_n: u64,
+
+    /// yescrypt r (block size) used to derive the DPK
+    #[arg(long, default_value_t = 32, global = true)]
+    dpk_r: u32,
+
+    /// yescrypt p (parallelism) used to derive the DPK
+    #[arg(long, default_value_t = 1, global = true)]
+    dpk_p: u32,
+
+    /// What the DPK is derived from; verification MUST use the same source as hashing
+    #[arg(long, value_enum, default_value_t = DpkSource::Mk, global = true)]
+    dpk_source: DpkSource,
+
+    /// How the MK is hashed into the phash; the fast hashes are keyed with salt 2 but make the
+    /// phash much cheaper to attack (see the README)
+    #[arg(long, value_enum, value_name = "ALGO", default_value_t = DigestAlgo::Yescrypt, global = true)]
+    digest_algo: DigestAlgo,
+
+    /// Also hash the MK with this digest, for migrating from one to the other; on verify, the
+    /// digest of --also-phash
+    #[arg(long, value_enum, value_name = "ALGO", global = true)]
+    also_digest: Option<DigestAlgo>,
+
+    /// yescrypt t (time cost) of every stage, which adds sequential work without using more
+    /// memory
+    #[arg(long, value_name = "N", default_value_t = 0, global = true)]
+    t: u32,
+
+    /// yescrypt flags bitfield (the mode) of every stage, in hex: 0 (classic scrypt), 1
+    /// (write-once/read-many) or 2f (read-write); for compatibility with other deployments only
+    #[arg(long, value_name = "HEX", default_value = "2f", value_parser = parse_yescrypt_flags, global = true)]
+    yescrypt_flags: Mode,
+
+    /// Accept params below the security floor and all-zero salts
+    #[arg(long, global = true)]
+    force: bool,
+
+    /// Refuse the bundles of --bundle-dir and serve whose MK or DPK stage takes more memory than
+    /// this, in MiB, rather than running yescrypt on them
+    #[arg(long, value_name = "MIB", default_value_t = 1024, value_parser = clap::value_parser!(u64).range(1..), global = true)]
+    max_bundle_memory: u64,
+
+    /// Take the password from the command line instead of STDIN (discouraged: it leaks to the
+    /// process table and the shell history)
+    #[arg(long, value_name = "STR", global = true)]
+    password: Option<String>,
+
+    /// Read the password from this file instead of STDIN; repeat it to build the password from
+    /// several files, in argument order
+    #[arg(long = "input-file", value_name = "PATH", global = true)]
+    input_files: Vec<PathBuf>,
+
+    /// Read the password up to the first occurrence of this byte (a character, an escape such as
+    /// \0 or \n, or 0xNN) instead of up to the end of the input
+    #[arg(long, value_name = "BYTE", value_parser = parse_delimiter, conflicts_with_all = ["password", "input_files", "prompt"], global = true)]
+    delimiter: Option<u8>,
+
+    /// Prompt for the password on the terminal without echoing it; hashing asks for it twice
+    #[arg(long, global = true)]
+    prompt: bool,
+
+    /// Read the password from this file instead of STDIN, as is (unlike --input-file)
+    #[arg(long, value_name = "PATH", global = true)]
+    password_file: Option<PathBuf>,
+
+    /// Read the password from this file descriptor instead of STDIN
+    #[arg(long, value_name = "FD", global = true)]
+    password_fd: Option<u32>,
+
+    /// Number of threads used to run the stages in parallel (default: one per CPU)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..), global = true)]
+    threads: Option<u16>,
+
+    /// Never use more than this many OS threads in total, whatever --threads or the number of CPUs
+    /// say
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..), global = true)]
+    max_threads: Option<u16>,
+
+    /// Print each line of the hash output as label=value (salt1, mk, phash, salt2, dpk, salt3)
+    #[arg(long, global = true)]
+    labeled: bool,
+
+    /// Print the MK's hash as a full yescrypt PHC string ($y$<params>$<salt 2>$<hash>) that
+    /// embeds the params it was made with
+    #[arg(long, global = true)]
+    phc: bool,
+
+    /// Write each field of the hash output to its own file in this directory (salt1, phash, ...)
+    /// instead of printing it
+    #[arg(long, value_name = "DIR", global = true)]
+    out_dir: Option<PathBuf>,
+
+    /// Replace the files already in --out-dir
+    #[arg(long, requires = "out_dir", global = true)]
+    overwrite: bool,
+
+    /// Write the hash output as a single binary bundle on STDOUT
+    #[arg(long, conflicts_with_all = ["labeled", "out_dir"], global = true)]
+    binary: bool,
+
+    /// Print the hash output or the verification result as a JSON object, and report errors and
+    /// warnings as JSON objects on STDERR
+    #[arg(long, conflicts_with_all = ["labeled", "out_dir", "binary"], global = true)]
+    json: bool,
+
+    /// Like --json, but print the object indented over several lines for humans
+    #[arg(long, conflicts_with_all = ["labeled", "out_dir", "binary"], global = true)]
+    json_pretty: bool,
+
+    /// Format of the DPK; raw, aes256, chacha20 and pem are only written to --out-dir
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = DpkFormat::Base64, conflicts_with = "binary", global = true)]
+    dpk_as: DpkFormat,
+
+    /// Print the hash output as shell export lines (PREFIX_SALT1=..., PREFIX_DPK=...) to eval
+    #[arg(long, value_name = "PREFIX", value_parser = parse_env_prefix, conflicts_with_all = ["labeled", "out_dir", "binary", "json", "json_pretty"], global = true)]
+    env_prefix: Option<String>,
+
+    /// Record in the binary bundle the version of MKDF and of the bundle format that made it, and
+    /// when
+    #[arg(long, requires = "binary", global = true)]
+    embed_metadata: bool,
+
+    /// Sign the binary bundle with this Ed25519 private key (PKCS#8 PEM)
+    #[cfg(feature = "sign")]
+    #[arg(long, value_name = "PATH", requires = "binary", global = true)]
+    sign_key: Option<PathBuf>,
+
+    /// Check the signature of the bundle against this Ed25519 public key (PEM) before verifying
+    /// the password
+    #[cfg(feature = "sign")]
+    #[arg(long, value_name = "PATH", requires = "bundle")]
+    verify_key: Option<PathBuf>,
+
+    /// Verify against this binary bundle instead of --s1, --s2, --s3 and --phash
+    #[arg(long, value_name = "PATH", requires = "verify", conflicts_with_all = ["s1", "s2", "s3", "phash"])]
+    bundle: Option<PathBuf>,
+
+    /// Verify the password against every binary bundle in this directory and report which ones
+    /// it opens
+    #[arg(long, value_name = "DIR", requires = "verify", conflicts_with_all = ["s1", "s2", "s3", "phash", "phash_stdin", "bundle", "prehash_only", "prehashed", "dpk_fingerprint"])]
+    bundle_dir: Option<PathBuf>,
+
+    /// Verify this many bundles of --bundle-dir at once (at most --max-threads)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..), requires = "bundle_dir")]
+    concurrency: Option<u16>,
+
+    /// Only derive the MK from the password and salt 1 and print it, for a backend to verify it
+    /// with --prehashed
+    #[arg(long, requires = "verify", conflicts_with_all = ["s2", "s3", "prehashed"])]
+    prehash_only: bool,
+
+    /// Read the MK printed by --prehash-only from STDIN instead of the password, and only run the
+    /// stages that follow it
+    #[arg(long, requires = "verify")]
+    prehashed: bool,
+
+    /// On a match, print the DPK alone (in any --dpk-as format) instead of Match and the DPK; on a
+    /// mismatch, print nothing
+    #[arg(long, requires = "verify", conflicts_with_all = ["json", "json_pretty", "prehash_only", "bundle_dir"])]
+    only_dpk: bool,
+
+    /// Verify against this SHA-256 fingerprint of the DPK (in hex) and --s3 instead of the phash
+    #[arg(long, value_name = "HEX", value_parser = parse_fingerprint, requires_all = ["verify", "s3"], conflicts_with_all = ["s2", "phash", "phash_stdin", "bundle", "prehash_only", "prehashed", "min_params"])]
+    dpk_fingerprint: Option<[u8; HASH_LEN]>,
+
+    /// Reject a phash whose params are below these, even if the password matches
+    #[arg(long, value_name = "N,R,P", value_parser = parse_min_params, requires = "verify")]
+    min_params: Option<(u64, u32, u32)>,
+
+    /// Refuse bundles (of --bundle or --bundle-dir) made in a format older than this version
+    #[arg(long, value_name = "N", requires = "verify")]
+    min_format_version: Option<u8>,
+
+    /// Derive the salts from the password instead of drawing them at random, so that the same
+    /// password always gives the same output (weaker: see the README)
+    #[arg(long, requires = "hash")]
+    deterministic_salt: bool,
+
+    /// Verify the hash output against the same password before printing it, and abort if it
+    /// doesn't match
+    #[arg(long, global = true)]
+    verify_after: bool,
+
+    /// Abort with exit code 124 if the derivation takes longer than this many milliseconds
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..), global = true)]
+    timeout_ms: Option<u64>,
+
+    /// Report how long the derivation took on STDERR
+    #[arg(long, global = true)]
+    time: bool,
+
+    /// Run the derivation this many times and report the average time under --time; the output
+    /// is still printed once
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..), hide = true, global = true)]
+    repeat: u32,
+
+    /// Print the estimated memory and time a hash run takes with the given params and stages,
+    /// without reading the password or deriving anything
+    #[arg(long, requires = "hash")]
+    cost_report: bool,
+
+    /// Print the params a hash run would use, resolved from the flags and their defaults, as a
+    /// JSON object without reading the password or deriving anything
+    #[arg(long, requires = "hash", conflicts_with = "cost_report")]
+    params_json: bool,
+
+    /// Don't print a newline after the last line of the output, so that command substitution
+    /// gets exactly the value
+    #[arg(short = 'n', long, global = true)]
+    no_trailing_newline: bool,
+
+    /// Print each exit code and its meaning
+    #[arg(long, hide = true)]
+    print_exit_codes: bool,
+
+    /// Don't warn when running as root, e.g. in containers where root is expected
+    #[arg(long, global = true)]
+    allow_root: bool,
+
+    /// Report extra diagnostics (e.g. where the salts come from) on STDERR
+    #[arg(long, global = true)]
+    verbose: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Hash the password read from STDIN again with fresh salts, optionally checking it against
+    /// the old bundle first
+    Rotate {
+        /// Old salt 1
+        #[arg(long, requires_all = ["s2", "phash"], value_parser = parse_salt_arg)]
+        s1: Option<String>,
+
+        /// Old salt 2
+        #[arg(long, requires_all = ["s1", "phash"], value_parser = parse_salt_arg)]
+        s2: Option<String>,
+
+        /// Old password's hash
+        #[arg(long, requires_all = ["s1", "s2"])]
+        phash: Option<String>,
+    },
+    /// Report which fields and params differ between two binary bundles
+    Diff {
+        /// Old bundle
+        old: PathBuf,
+
+        /// New bundle
+        new: PathBuf,
+    },
+    /// Interactively pick DPK params for a target unlock time and memory budget
+    Tune,
+    /// Print fresh random salts, without reading a password or deriving anything
+    Gensalt {
+        /// Number of salts to print
+        #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+        count: u32,
+    },
+    /// Check that MKDF reproduces the hashes of another yescrypt tool, from a file of
+    /// password,salt,expected_hash rows
+    CompatCheck {
+        /// File of rows, with the salt in hex and the hash either bare (made with the MK params)
+        /// or as a PHC string
+        file: PathBuf,
+    },
+    /// Verify the password read from STDIN against a binary bundle and, if it matches, write the
+    /// DPK alone to a file for an encryption tool
+    Unlock {
+        /// Bundle to verify against
+        #[arg(long, value_name = "PATH")]
+        bundle: PathBuf,
+
+        /// File (or /dev/fd/N) to write the DPK to, with 0600 permissions
+        #[arg(long, value_name = "PATH")]
+        dpk_out: PathBuf,
+
+        /// Check the bundle's signature against this Ed25519 public key (PKCS#8 PEM) before
+        /// checking the password
+        #[cfg(feature = "sign")]
+        #[arg(long, value_name = "PATH")]
+        verify_key: Option<PathBuf>,
+    },
+    /// Serve verification requests on a Unix domain socket
+    #[cfg(all(target_os = "linux", feature = "server"))]
+    Serve {
+        /// Path of the socket to listen on; a stale socket left there is replaced
+        #[arg(long, value_name = "PATH")]
+        socket: PathBuf,
+
+        /// Number of requests each client (user ID) may make per minute
+        #[arg(long, value_name = "N", default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+        rate_limit: u32,
+
+        /// Refuse bundles made in a format older than this version
+        #[arg(long, value_name = "N")]
+        min_format_version: Option<u8>,
+    },
+    /// Diagnostic, not for normal use: time many verifications of the password read from STDIN
+    /// and of a wrong one of the same length, and report whether their timings differ
+    TimingTest {
+        /// Number of verifications of each password
+        #[arg(long, value_name = "N", default_value_t = 50, value_parser = clap::value_parser!(u32).range(2..))]
+        runs: u32,
+    },
+    /// Print a single field of a binary bundle, without reading a password
+    Extract {
+        /// Bundle to read the field from
+        #[arg(long, value_name = "PATH")]
+        bundle: PathBuf,
+
+        /// Field to print; salts use --salt-encoding, the DPK --dpk-as, and the MK and phash the
+        /// same base64 as the hash output
+        #[arg(long, value_enum)]
+        field: BundleField,
+    },
+    /// Hash or verify a password through an interactive form, with the params of the flags or a
+    /// preset
+    #[cfg(feature = "tui")]
+    Tui,
+    /// Print each field of a yescrypt PHC string (id, params, salt and hash)
+    Inspect {
+        /// PHC string to inspect, either made by MKDF or by another yescrypt implementation
+        #[arg(long)]
+        phash: String,
+    },
+}
+
+/// How the hash output is laid out
+struct OutputFormat {
+    salt_encoding: SaltEncoding,
+    hex_case: HexCase,
+    salt_group: Option<u16>,
+    labeled: bool,
+    phc: bool,
+    binary: bool,
+    json: bool,
+    env_prefix: Option<String>,
+    dpk_as: DpkFormat,
+    out_dir: Option<PathBuf>,
+    overwrite: bool,
+    embed_metadata: bool,
+    #[cfg(feature = "sign")]
+    sign_key: Option<ed25519_dalek::SigningKey>,
+}
+
+/// yescrypt params of each stage; the MK and its hash share the same params
+#[derive(Clone, Copy)]
+struct DerivationParams {
+    mk: StageParams,
+    dpk: StageParams,
+    dpk_source: DpkSource,
+    digest: DigestAlgo,
+}
+
+/// yescrypt params of one stage, kept apart from `Params` since it doesn't expose all of them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct StageParams {
+    mode: Mode,
+    n: u64,
+    r: u32,
+    p: u32,
+    t: u32,
+}
+
+impl StageParams {
+    fn yescrypt(&self) -> Result<Params, yescrypt::Error> {
+        Params::new_with_all_params(self.mode, self.n, self.r, self.p, self.t, 0)
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum BundleField {
+    Salt1,
+    Mk,
+    Phash,
+    Salt2,
+    Dpk,
+    Salt3,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Stage {
+    /// Print the MK itself
+    Mk,
+    /// Print the MK's hash and salt 2
+    Digest,
+    /// Print the DPK and salt 3
+    Dpk,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum DpkSource {
+    /// Derive the DPK from the password itself, independently of the MK
+    Password,
+    /// Derive the DPK from the MK, like the MK's hash
+    Mk,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum DigestAlgo {
+    /// A second yescrypt pass with the MK params
+    Yescrypt,
+    /// HMAC-SHA-256 keyed with salt 2
+    Sha256,
+    /// HMAC-SHA-512 keyed with salt 2
+    Sha512,
+    /// BLAKE3 in keyed mode, with a key derived from salt 2
+    Blake3,
 }
+
+impl DigestAlgo {
+    fn len(self) -> usize {
+        match self {
+            DigestAlgo::Sha512 => 64,
+            _ => HASH_LEN,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum DpkFormat {
+    /// yescrypt's base64, as printed by default
+    Base64,
+    /// Lowercase hex
+    Hex,
+    /// The raw bytes
+    Raw,
+    /// The raw bytes of a 32-byte AES-256 key
+    Aes256,
+    /// The raw bytes of a 32-byte ChaCha20 key
+    Chacha20,
+    /// Standard base64 wrapped in a PEM block
+    Pem,
+}
+
+impl DpkFormat {
+    /// Formats that don't fit on one line of the output, and so are only written to --out-dir
+    fn is_binary(self) -> bool {
+        !matches!(self, DpkFormat::Base64 | DpkFormat::Hex)
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum SaltEncoding {
+    Hex,
+    /// URL-safe base64 without padding
+    Base64url,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum HexCase {
+    Lower,
+    Upper,
+}
+
 fn main() {
-    let args = Args::parse();
-    if args.hash && args.verify {
-        eprintln!("Exactly either -h or -v must be specified.");
-        std::process::exit(64);
-    } else if !args.hash && !args.verify {
-        eprintln!("Exactly either -h or -v must be specified.");
-        std::process::exit(64);
-    }
-    // Read password from STDIN
-    let password = readpw()
-        .map_err(|e| {
-            eprintln!("failed to read password: {}", e);
-            std::process::exit(2);
-        })
-        .unwrap();
+    // fail unwinds rather than exiting, so that the secrets held along the way are dropped (and
+    // wiped) on the way out like they are when run returns
+    let code = match std::panic::catch_unwind(run) {
+        Ok(code) => code,
+        Err(payload) => match payload.downcast::<Exit>() {
+            Ok(exit) => exit.0,
+            Err(payload) => std::panic::resume_unwind(payload),
+        },
+    };
+    std::process::exit(code);
+}
 
+fn run() -> i32 {
+    let mut args = Args::parse();
+    args.json |= args.json_pretty;
+    JSON.store(args.json, Ordering::Relaxed);
+    JSON_PRETTY.store(args.json_pretty, Ordering::Relaxed);
+    NO_TRAILING_NEWLINE.store(args.no_trailing_newline, Ordering::Relaxed);
+    VERBOSE.store(args.verbose, Ordering::Relaxed);
+    #[cfg(unix)]
+    if !args.allow_root && rustix::process::geteuid().is_root() {
+        warn("running as root widens the damage a bug could do; consider a dedicated user (--allow-root silences this)");
+    }
+    if args.print_exit_codes {
+        let lines: Vec<String> = EXIT_CODES
+            .iter()
+            .map(|(code, meaning)| format!("{code}\t{meaning}"))
+            .collect();
+        print_output(&lines.join("\n"));
+        return EXIT_OK;
+    }
+    // These are global so that rotate takes them too, which keeps clap from requiring --hash for
+    // them: subcommands have no --hash to require
+    if !args.hash && args.command.is_none() {
+        let hash_only = [
+            (!args.stages.is_empty(), "--stages"),
+            (args.labeled, "--labeled"),
+            (args.phc, "--phc"),
+            (args.verify_after, "--verify-after"),
+        ];
+        if let Some((_, flag)) = hash_only.iter().find(|(given, _)| *given) {
+            fail(EXIT_USAGE, format!("{flag} needs --hash"));
+        }
+    }
+    // clap lets a required arg go missing when it conflicts with one that is given, so --hash
+    // waives the requires = "verify" of these
     if args.hash {
-        hash_password(&password);
-        std::process::exit(0);
-    } else {
-        let (s1, s2, s3) = (args.s1.unwrap(), args.s2.unwrap(), args.s3.unwrap());
-        if s1.len() != 32 || s2.len() != 32 || s3.len() != 32 {
-            eprintln!("The salts must be 32 characters long (16 bytes long)");
-            std::process::exit(64);
-        }
-        let (salt1, (salt2, salt3)) =
-            join(|| get_salt(s1), || join(|| get_salt(s2), || get_salt(s3)));
-        verify_password(
-            &password,
-            &salt1,
-            &salt2,
-            &salt3,
-            args.phash.unwrap().as_str(),
+        let verify_only = [
+            (args.s1.is_some(), "--s1"),
+            (args.s2.is_some(), "--s2"),
+            (args.s3.is_some(), "--s3"),
+            (args.phash.is_some(), "--phash"),
+            (args.also_phash.is_some(), "--also-phash"),
+            (args.phash_stdin, "--phash-stdin"),
+            (args.bundle.is_some(), "--bundle"),
+            (args.bundle_dir.is_some(), "--bundle-dir"),
+            (args.prehash_only, "--prehash-only"),
+            (args.prehashed, "--prehashed"),
+            (args.only_dpk, "--only-dpk"),
+            (args.dpk_fingerprint.is_some(), "--dpk-fingerprint"),
+            (args.min_params.is_some(), "--min-params"),
+            (args.min_format_version.is_some(), "--min-format-version"),
+        ];
+        if let Some((_, flag)) = verify_only.iter().find(|(given, _)| *given) {
+            fail(EXIT_USAGE, format!("{flag} needs -v"));
+        }
+    }
+    if args.concurrency.is_some() && args.bundle_dir.is_none() {
+        fail(EXIT_USAGE, "--concurrency needs --bundle-dir");
+    }
+    if args.min_format_version.is_some() && args.bundle.is_none() && args.bundle_dir.is_none() {
+        fail(
+            EXIT_USAGE,
+            "--min-format-version needs --bundle or --bundle-dir",
+        );
+    }
+    // Bundles can be compared without the password
+    if let Some(Command::Diff { old, new }) = &args.command {
+        let same = diff_bundles(&read_bundle(old, None), &read_bundle(new, None), args.json);
+        return if same { EXIT_OK } else { EXIT_MISMATCH };
+    }
+    if let Some(Command::Extract { bundle, field }) = &args.command {
+        extract_field(&read_bundle(bundle, None), *field, &args);
+        return EXIT_OK;
+    }
+    if let Some(Command::Inspect { phash }) = &args.command {
+        inspect_phc(phash, args.hex_case, args.json);
+        return EXIT_OK;
+    }
+    #[cfg(all(target_os = "linux", feature = "server"))]
+    if let Some(Command::Serve {
+        socket,
+        rate_limit,
+        min_format_version,
+    }) = &args.command
+    {
+        server::serve(
+            socket,
+            *rate_limit,
+            *min_format_version,
+            args.max_bundle_memory,
+            args.force,
+        );
+    }
+    if let Some(Command::Gensalt { count }) = args.command {
+        let salts: Vec<String> = (0..count)
+            .map(|_| encode_salt(&generate_salt(), args.salt_encoding, args.hex_case))
+            .collect();
+        if args.json {
+            print_json(serde_json::json!({"salts": salts}));
+        } else {
+            let salts: Vec<String> = salts
+                .iter()
+                .map(|salt| group_salt(salt, args.salt_group))
+                .collect();
+            print_output(&salts.join("\n"));
+        }
+        return EXIT_OK;
+    }
+    if args.command.is_none() && args.hash == args.verify {
+        fail(EXIT_USAGE, "Exactly either -h or -v must be specified.");
+    }
+    // The bundles of --bundle-dir are the only work that --concurrency spreads over the pool
+    init_thread_pool(args.concurrency.or(args.threads), args.max_threads);
+    let params = DerivationParams {
+        mk: build_params(
+            "MK",
+            args.yescrypt_flags,
+            args.mk_n,
+            args.mk_r,
+            args.mk_p,
+            args.t,
+            args.force,
+        ),
+        dpk: build_params(
+            "DPK",
+            args.yescrypt_flags,
+            args.dpk_n,
+            args.dpk_r,
+            args.dpk_p,
+            args.t,
+            args.force,
+        ),
+        dpk_source: args.dpk_source,
+        digest: args.digest_algo,
+    };
+    if args.phc && params.digest != DigestAlgo::Yescrypt {
+        fail(EXIT_USAGE, "--phc only applies to the yescrypt digest");
+    }
+    if args.also_digest == Some(params.digest) {
+        fail(EXIT_USAGE, "--also-digest must differ from --digest-algo");
+    }
+    if let Some(Command::CompatCheck { file }) = &args.command {
+        let same = compat_check(file, &params.mk, args.force, args.json);
+        return if same { EXIT_OK } else { EXIT_MISMATCH };
+    }
+    if let Some(Command::Tune) = args.command {
+        tune(&params);
+        return EXIT_OK;
+    }
+    #[cfg(feature = "tui")]
+    if let Some(Command::Tui) = args.command {
+        tui::run(&params, args.salt_encoding, args.hex_case, args.force);
+        return EXIT_OK;
+    }
+    let stages = if args.stages.is_empty() {
+        vec![Stage::Digest, Stage::Dpk]
+    } else {
+        args.stages
+    };
+    // Verification warns once it knows the params it derives the DPK with, which may be a bundle's
+    let hashing = args.hash || matches!(args.command, Some(Command::Rotate { .. }));
+    if hashing && stages.contains(&Stage::Dpk) {
+        warn_weak_params(&params.dpk);
+    }
+    if args.cost_report {
+        print_cost_report(&stages, &params, args.json);
+        return EXIT_OK;
+    }
+    if args.params_json {
+        let name =
+            |value: Option<clap::builder::PossibleValue>| value.unwrap().get_name().to_owned();
+        let report = serde_json::json!({
+            "kdf": "yescrypt",
+            "stages": stages.iter().map(|s| name(s.to_possible_value())).collect::<Vec<_>>(),
+            "salt_len": SALT_LEN,
+            "salt_encoding": name(args.salt_encoding.to_possible_value()),
+            "hex_case": name(args.hex_case.to_possible_value()),
+            "deterministic_salt": args.deterministic_salt,
+            "phc": args.phc,
+            "also_digest": args.also_digest.map(|digest| name(digest.to_possible_value())),
+            "params": params_json(&params),
+        });
+        print_json(report);
+        return EXIT_OK;
+    }
+
+    // Read password from STDIN unless it was given inline or through files
+    let password = Zeroizing::new(match args.password {
+        Some(password) => {
+            warn(
+                "--password exposes the password to other users through the process \
+                 table and may be saved in the shell history; only use it in trusted test setups",
+            );
+            password.into_bytes()
+        }
+        None if !args.input_files.is_empty() => read_input_files(&args.input_files),
+        // Verifying only needs the password once; a typo there is just a mismatch
+        None if args.prompt => {
+            prompt_password(args.hash || matches!(args.command, Some(Command::Rotate { .. })))
+        }
+        None => {
+            let input: Box<dyn Read> = match (&args.password_file, args.password_fd) {
+                (Some(path), _) => {
+                    Box::new(retry_io(|| fs::File::open(path)).unwrap_or_else(|e| {
+                        fail(EXIT_IO, format!("failed to open {}: {}", path.display(), e))
+                    }))
+                }
+                (_, Some(fd)) => Box::new(open_fd(fd)),
+                _ => Box::new(io::stdin()),
+            };
+            readpw(input, args.delimiter)
+                .unwrap_or_else(|e| fail(EXIT_IO, format!("failed to read password: {}", e)))
+        }
+    });
+    if args.phash_stdin {
+        let phash = readpw(io::stdin(), None)
+            .unwrap_or_else(|e| fail(EXIT_IO, format!("failed to read the phash: {}", e)));
+        let phash = String::from_utf8(phash)
+            .unwrap_or_else(|_| fail(EXIT_USAGE, "The phash read from STDIN is not valid UTF-8"));
+        args.phash = Some(phash.trim().to_string());
+    }
+    if let Some(Command::TimingTest { runs }) = args.command {
+        let suspicious = timing_test(&password, &params, runs, args.json);
+        return if suspicious { EXIT_MISMATCH } else { EXIT_OK };
+    }
+
+    let format = OutputFormat {
+        salt_encoding: args.salt_encoding,
+        hex_case: args.hex_case,
+        salt_group: args.salt_group,
+        labeled: args.labeled,
+        phc: args.phc,
+        binary: args.binary,
+        json: args.json,
+        env_prefix: args.env_prefix,
+        dpk_as: args.dpk_as,
+        out_dir: args.out_dir,
+        overwrite: args.overwrite,
+        embed_metadata: args.embed_metadata,
+        #[cfg(feature = "sign")]
+        sign_key: args.sign_key.as_deref().map(load_signing_key),
+    };
+    if let Some(dir) = &format.out_dir {
+        prepare_out_dir(dir, format.overwrite);
+    }
+    let unlock = matches!(args.command, Some(Command::Unlock { .. }));
+    if format.dpk_as.is_binary() && format.out_dir.is_none() && !unlock && !args.only_dpk {
+        fail(
+            EXIT_USAGE,
+            "--dpk-as raw, aes256, chacha20 and pem need --out-dir to write the DPK to",
         );
-        std::process::exit(0);
+    }
+    if format.env_prefix.is_some()
+        && (args.hash || args.command.is_some())
+        && stages.contains(&Stage::Dpk)
+    {
+        warn("exporting the DPK to the environment exposes it to every child process of the shell");
+    }
+
+    let phc = format.phc && !format.binary;
+    let (repeat, time) = (args.repeat, args.time);
+    let deterministic = args.deterministic_salt;
+    if let Some(timeout) = args.timeout_ms {
+        let timeout = Duration::from_millis(timeout);
+        let _ = DEADLINE.set((Instant::now() + timeout, timeout));
+    }
+
+    if let Some(Command::Rotate { s1, s2, phash }) = args.command {
+        // Only rotate a bundle the password actually opens
+        if let (Some(s1), Some(s2), Some(phash)) = (s1, s2, phash) {
+            check_salt_lengths(&[&s1, &s2], args.salt_encoding);
+            let (salt1, salt2) = join(
+                || get_salt(s1, args.salt_encoding),
+                || get_salt(s2, args.salt_encoding),
+            );
+            check_salt_entropy(&[&salt1, &salt2], args.force);
+            check_phash(&phash, params.digest);
+            check_phc_salt(&phash, &salt2);
+            let old_params = DerivationParams {
+                mk: phash_mk_params(&phash, &params.mk, args.force),
+                ..params
+            };
+            if check_password(&password, &salt1, &salt2, &phash, None, &old_params).is_none() {
+                fail(
+                    EXIT_MISMATCH,
+                    "The password does not match the old bundle; not rotating",
+                );
+            }
+        }
+        let output = timed(repeat, time, || {
+            hash_password(
+                &password,
+                &stages,
+                &params,
+                args.also_digest,
+                phc,
+                deterministic,
+            )
+        });
+        if args.verify_after {
+            self_verify(&password, &output, &params);
+        }
+        print_hash_output(&output, &params, &format);
+        EXIT_OK
+    } else if let Some(Command::Unlock {
+        bundle, dpk_out, ..
+    }) = &args.command
+    {
+        let bundle = read_bundle(bundle, None);
+        if let Err(e) = check_bundle_params(&bundle, args.force) {
+            fail(EXIT_USAGE, e);
+        }
+        #[cfg(feature = "sign")]
+        if let Some(Command::Unlock {
+            verify_key: Some(key),
+            ..
+        }) = &args.command
+        {
+            check_bundle_signature(&bundle, &load_verifying_key(key));
+        }
+        report_metadata(&bundle);
+        let (Some(phash), Some(salt2), Some(salt3)) = (bundle.phash, bundle.salt2, bundle.salt3)
+        else {
+            fail(
+                EXIT_USAGE,
+                "The bundle must hold the phash and salts 2 and 3 to unlock it",
+            );
+        };
+        let phash = encode_hash(&phash);
+        let phash2 = bundle
+            .phash2
+            .map(|(digest, phash)| (digest, encode_hash(&phash)));
+        let params = DerivationParams {
+            mk: bundle.mk_params,
+            dpk: bundle.dpk_params,
+            dpk_source: bundle.dpk_source,
+            digest: bundle.digest,
+        };
+        warn_weak_params(&params.dpk);
+        check_phash(&phash, params.digest);
+        if let Some((digest, phash)) = &phash2 {
+            check_phash(phash, *digest);
+        }
+        check_salt_entropy(&[&bundle.salt1, &salt2, &salt3], args.force);
+        let also = phash2
+            .as_ref()
+            .map(|(digest, phash)| (*digest, phash.as_str()));
+        let dpk = timed(repeat, time, || {
+            verify_password(
+                &password,
+                &bundle.salt1,
+                &salt2,
+                &salt3,
+                &phash,
+                also,
+                &params,
+            )
+        });
+        if let Some(dpk) = &dpk {
+            // Always replaced, since unlocking again has to refresh the key
+            if let Err(e) = write_field(dpk_out, &format_dpk(dpk, args.dpk_as), true) {
+                fail(
+                    EXIT_IO,
+                    format!("failed to write {}: {}", dpk_out.display(), e),
+                );
+            }
+        }
+        if args.json {
+            print_json(serde_json::json!({"match": dpk.is_some()}));
+        } else {
+            print_output(if dpk.is_some() { "Match" } else { "Mismatch" });
+        }
+        if dpk.is_some() {
+            EXIT_OK
+        } else {
+            EXIT_MISMATCH
+        }
+    } else if args.hash {
+        let output = timed(repeat, time, || {
+            hash_password(
+                &password,
+                &stages,
+                &params,
+                args.also_digest,
+                phc,
+                deterministic,
+            )
+        });
+        if args.verify_after {
+            self_verify(&password, &output, &params);
+        }
+        print_hash_output(&output, &params, &format);
+        EXIT_OK
+    } else if args.prehash_only {
+        let (salt1, mk_params) = match &args.bundle {
+            Some(path) => {
+                let bundle = read_bundle(path, args.min_format_version);
+                if let Err(e) = check_bundle_params(&bundle, args.force) {
+                    fail(EXIT_USAGE, e);
+                }
+                #[cfg(feature = "sign")]
+                if let Some(key) = &args.verify_key {
+                    check_bundle_signature(&bundle, &load_verifying_key(key));
+                }
+                (bundle.salt1, bundle.mk_params)
+            }
+            None => {
+                let Some(s1) = args.s1 else {
+                    fail(EXIT_USAGE, "--prehash-only needs --s1 or --bundle");
+                };
+                check_salt_lengths(&[&s1], args.salt_encoding);
+                let salt1 = get_salt(s1, args.salt_encoding);
+                check_salt_entropy(&[&salt1], args.force);
+                let mk_params = match &args.phash {
+                    Some(phash) => {
+                        check_phash(phash, params.digest);
+                        phash_mk_params(phash, &params.mk, args.force)
+                    }
+                    None => params.mk,
+                };
+                (salt1.to_vec(), mk_params)
+            }
+        };
+        let mk = timed(repeat, time, || {
+            Zeroizing::new(expect_kdf(generate_hash_mk(&password, &salt1, &mk_params)))
+        });
+        if args.json {
+            let report = serde_json::json!({
+                "mk": mk.as_str(),
+                "stages": ["mk"],
+                "params": {"mk": stage_params_json(&mk_params)},
+            });
+            print_json(report);
+        } else {
+            print_output(&mk);
+        }
+        EXIT_OK
+    } else if let Some(dir) = &args.bundle_dir {
+        let parallel = args.concurrency.is_some() && !SERIAL.load(Ordering::Relaxed);
+        let matched = verify_bundle_dir(dir, parallel, args.json, |data| {
+            check_bundle(
+                &password,
+                data,
+                args.min_params,
+                args.min_format_version,
+                args.max_bundle_memory,
+                args.force,
+            )
+        });
+        if matched {
+            EXIT_OK
+        } else {
+            EXIT_MISMATCH
+        }
+    } else if let Some(fingerprint) = args.dpk_fingerprint {
+        // Salt 1 only matters when the DPK is derived from the MK
+        let needs_mk = params.dpk_source == DpkSource::Mk;
+        let s1 = match args.s1 {
+            Some(s1) if needs_mk => Some(s1),
+            None if needs_mk => fail(
+                EXIT_USAGE,
+                "--dpk-fingerprint needs --s1 to derive the DPK from the MK",
+            ),
+            _ => None,
+        };
+        let s3 = args.s3.expect("clap requires --s3");
+        let given: Vec<&str> = s1.iter().chain([&s3]).map(String::as_str).collect();
+        check_salt_lengths(&given, args.salt_encoding);
+        let salt1 = s1.map(|s1| get_salt(s1, args.salt_encoding));
+        let salt3 = get_salt(s3, args.salt_encoding);
+        let salts: Vec<&[u8]> = salt1.iter().chain([&salt3]).map(|s| &s[..]).collect();
+        check_salt_entropy(&salts, args.force);
+        warn_weak_params(&params.dpk);
+        if args.dpk_as.is_binary() && !args.only_dpk {
+            fail(
+                EXIT_USAGE,
+                "verification can only print the DPK as base64 or hex, unless --only-dpk is given",
+            );
+        }
+        let dpk = timed(repeat, time, || {
+            let mk = Zeroizing::new(salt1.map_or(String::new(), |salt1| {
+                expect_kdf(generate_hash_mk(&password, &salt1, &params.mk))
+            }));
+            let dpk = expect_kdf(derive_dpk(&password, &mk, &salt3, &params));
+            ct_eq(&dpk_fingerprint(&dpk), &fingerprint).then_some(dpk)
+        });
+        let stages = if needs_mk {
+            vec!["mk", "dpk"]
+        } else {
+            vec!["dpk"]
+        };
+        print_verify_result(dpk, stages, &params, args.dpk_as, args.json, args.only_dpk)
+    } else {
+        let (salts, phash, phash2, params) = match &args.bundle {
+            Some(path) => {
+                let bundle = read_bundle(path, args.min_format_version);
+                if let Err(e) = check_bundle_params(&bundle, args.force) {
+                    fail(EXIT_USAGE, e);
+                }
+                #[cfg(feature = "sign")]
+                if let Some(key) = &args.verify_key {
+                    check_bundle_signature(&bundle, &load_verifying_key(key));
+                }
+                report_metadata(&bundle);
+                let (Some(phash), Some(salt2), Some(salt3)) =
+                    (bundle.phash, bundle.salt2, bundle.salt3)
+                else {
+                    fail(
+                        EXIT_USAGE,
+                        "The bundle must hold the phash and salts 2 and 3 to verify against it",
+                    );
+                };
+                let params = DerivationParams {
+                    mk: bundle.mk_params,
+                    dpk: bundle.dpk_params,
+                    dpk_source: bundle.dpk_source,
+                    digest: bundle.digest,
+                };
+                let phash2 = bundle
+                    .phash2
+                    .map(|(digest, phash)| (digest, encode_hash(&phash)));
+                let salts = [bundle.salt1, salt2, salt3];
+                (salts, encode_hash(&phash), phash2, params)
+            }
+            None => {
+                let (Some(s1), Some(s2), Some(s3), Some(phash)) =
+                    (args.s1, args.s2, args.s3, args.phash)
+                else {
+                    fail(
+                        EXIT_USAGE,
+                        "Verification needs --s1, --s2, --s3 and --phash, or --bundle",
+                    );
+                };
+                check_salt_lengths(&[&s1, &s2, &s3], args.salt_encoding);
+                let encoding = args.salt_encoding;
+                let (salt1, (salt2, salt3)) = join(
+                    || get_salt(s1, encoding),
+                    || join(|| get_salt(s2, encoding), || get_salt(s3, encoding)),
+                );
+                let salts = [salt1.to_vec(), salt2.to_vec(), salt3.to_vec()];
+                let phash2 = args.also_digest.zip(args.also_phash);
+                (salts, phash, phash2, params)
+            }
+        };
+        check_phash(&phash, params.digest);
+        let params = DerivationParams {
+            mk: phash_mk_params(&phash, &params.mk, args.force),
+            ..params
+        };
+        if let Some(min_params) = args.min_params {
+            check_min_params(&phash, &params.mk, min_params);
+        }
+        warn_weak_params(&params.dpk);
+        let [salt1, salt2, salt3] = &salts;
+        check_salt_entropy(&[salt1, salt2, salt3], args.force);
+        check_phc_salt(&phash, salt2);
+        if let Some((digest, phash)) = &phash2 {
+            check_phash(phash, *digest);
+            check_phc_salt(phash, salt2);
+        }
+        let also = phash2
+            .as_ref()
+            .map(|(digest, phash)| (*digest, phash.as_str()));
+        if args.prehashed && params.dpk_source == DpkSource::Password {
+            fail(
+                EXIT_USAGE,
+                "--prehashed can't derive a DPK from the password (--dpk-source password)",
+            );
+        }
+        if args.dpk_as.is_binary() && !args.only_dpk {
+            fail(
+                EXIT_USAGE,
+                "verification can only print the DPK as base64 or hex, unless --only-dpk is given",
+            );
+        }
+        let dpk = timed(repeat, time, || {
+            if args.prehashed {
+                // STDIN held the MK from --prehash-only rather than the password
+                let mk = String::from_utf8_lossy(&password);
+                expect_kdf(check_mk(&mk, salt2, &phash, also, &params))
+                    .then(|| expect_kdf(derive_dpk(&[], &mk, salt3, &params)))
+            } else {
+                verify_password(&password, salt1, salt2, salt3, &phash, also, &params)
+            }
+        });
+        let mut stages = if args.prehashed { vec![] } else { vec!["mk"] };
+        stages.push("digest");
+        if dpk.is_some() {
+            stages.push("dpk");
+        }
+        print_verify_result(dpk, stages, &params, args.dpk_as, args.json, args.only_dpk)
     }
 }
 
-fn hash_password(password: &Vec<u8>) {
-    let (salt1, (salt2, salt3)) = join(
-        || generate_salt(),
-        || join(|| generate_salt(), || generate_salt()),
-    );
+fn print_verify_result(
+    dpk: Option<Zeroizing<String>>,
+    stages: Vec<&str>,
+    params: &DerivationParams,
+    dpk_as: DpkFormat,
+    json: bool,
+    only_dpk: bool,
+) -> i32 {
+    if verbose() {
+        report_verify_params(&stages, params);
+    }
+    if only_dpk {
+        let Some(dpk) = dpk else {
+            return EXIT_MISMATCH;
+        };
+        let dpk = format_dpk(&dpk, dpk_as);
+        if dpk_as.is_binary() {
+            if let Err(e) = io::stdout().write_all(&dpk) {
+                fail(EXIT_IO, format!("failed to write the DPK: {}", e));
+            }
+        } else {
+            print_output(std::str::from_utf8(&dpk).unwrap());
+        }
+        return EXIT_OK;
+    }
+    let dpk = dpk.map(|dpk| format_dpk(&dpk, dpk_as));
+    let dpk = dpk.as_deref().map(|dpk| std::str::from_utf8(dpk).unwrap());
+    if json {
+        // Which stages ran lets monitoring tell a split verification from a full one
+        let mut report = serde_json::json!({
+            "match": dpk.is_some(),
+            "stages": stages,
+            "params": params_json(params),
+        });
+        if let Some(dpk) = dpk {
+            report["dpk"] = dpk.into();
+        }
+        print_json(report);
+    } else {
+        match &dpk {
+            Some(dpk) => print_output(&Zeroizing::new(format!("Match\n{dpk}"))),
+            None => print_output("Mismatch"),
+        }
+    }
+    if dpk.is_some() {
+        EXIT_OK
+    } else {
+        EXIT_MISMATCH
+    }
+}
 
-    // Hash the password
-    let mk = generate_hash_mk(&password, &salt1);
-    for b in salt1 {
-        print!("{:02x}", b);
+fn dpk_fingerprint(dpk: &str) -> [u8; HASH_LEN] {
+    Sha256::digest(decode_hash(dpk)).into()
+}
+
+// Looks at every byte whatever the outcome, so the time taken doesn't tell how much of the
+// fingerprint matched
+fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    let diff = a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y));
+    std::hint::black_box(diff) == 0 && a.len() == b.len()
+}
+
+fn parse_fingerprint(s: &str) -> Result<[u8; HASH_LEN], String> {
+    decode_hex(s).map_err(|e| format!("invalid fingerprint: {e}"))
+}
+
+// rayon's global pool can only be configured once per process, so later calls are no-ops and the
+// first thread count wins. With a single thread there is nothing to gain from the pool, so it isn't
+// even started and the stages run one after the other
+fn init_thread_pool(threads: Option<u16>, max_threads: Option<u16>) {
+    static POOL: OnceLock<()> = OnceLock::new();
+    POOL.get_or_init(|| {
+        let available = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let wanted = threads.map_or(available, usize::from);
+        let count = max_threads.map_or(wanted, |max| wanted.min(max.into()));
+        if verbose() && count < wanted {
+            info(format!("threads: capped to {count} by --max-threads"));
+        }
+        if count == 1 {
+            SERIAL.store(true, Ordering::Relaxed);
+        } else if threads.is_some() || max_threads.is_some() {
+            let mut builder = rayon::ThreadPoolBuilder::new().num_threads(count);
+            // Otherwise the main thread would wait beside the pool as one thread too many
+            if max_threads.is_some() {
+                builder = builder.use_current_thread();
+            }
+            builder.build_global().unwrap_or_else(|e| {
+                fail(EXIT_IO, format!("failed to set up the thread pool: {}", e))
+            });
+        }
+    });
+}
+
+// Same as rayon::join, but runs both closures on the current thread when there is only one
+fn join<A, B, RA, RB>(a: A, b: B) -> (RA, RB)
+where
+    A: FnOnce() -> RA + Send,
+    B: FnOnce() -> RB + Send,
+    RA: Send,
+    RB: Send,
+{
+    if SERIAL.load(Ordering::Relaxed) {
+        (a(), b())
+    } else {
+        rayon::join(a, b)
     }
-    println!();
+}
+
+/// Everything a hash run produces; the fields of the stages that weren't asked for are empty
+struct HashOutput {
+    salt1: [u8; SALT_LEN],
+    mk: Option<Zeroizing<String>>,
+    phash: Option<String>,
+    phash2: Option<(DigestAlgo, String)>,
+    salt2: Option<[u8; SALT_LEN]>,
+    dpk: Option<Zeroizing<String>>,
+    salt3: Option<[u8; SALT_LEN]>,
+}
+
+fn hash_password(
+    password: &[u8],
+    stages: &[Stage],
+    params: &DerivationParams,
+    also_digest: Option<DigestAlgo>,
+    phc: bool,
+    deterministic: bool,
+) -> HashOutput {
+    let (salt1, salt2, salt3) = if deterministic {
+        let [salt1, salt2, salt3] = expect_kdf(derive_salts(password, &params.mk));
+        (salt1, salt2, salt3)
+    } else {
+        let (salt1, (salt2, salt3)) = join(generate_salt, || join(generate_salt, generate_salt));
+        (salt1, salt2, salt3)
+    };
+
+    // Hash the password
+    let mk = Zeroizing::new(expect_kdf(generate_hash_mk(password, &salt1, &params.mk)));
 
-    // Hash the MK and derive the DPK:
+    // Hash the MK and derive the DPK, skipping the stages that weren't asked for:
     let (hash_mk, dpk) = join(
-        || generate_hash_mk(mk.as_bytes(), &salt2),
-        || derive_dpk(mk.as_bytes(), &salt3),
+        || {
+            stages.contains(&Stage::Digest).then(|| {
+                let also = also_digest.map(|digest| {
+                    let params = DerivationParams { digest, ..*params };
+                    (digest, expect_kdf(digest_mk(&mk, &salt2, &params, false)))
+                });
+                (expect_kdf(digest_mk(&mk, &salt2, params, phc)), also)
+            })
+        },
+        || {
+            stages
+                .contains(&Stage::Dpk)
+                .then(|| expect_kdf(derive_dpk(password, &mk, &salt3, params)))
+        },
     );
-    println!("{hash_mk}");
-    for b in salt2 {
-        print!("{:02x}", b);
+
+    HashOutput {
+        salt1,
+        mk: stages.contains(&Stage::Mk).then_some(mk),
+        salt2: hash_mk.is_some().then_some(salt2),
+        phash2: hash_mk.as_ref().and_then(|(_, also)| also.clone()),
+        phash: hash_mk.map(|(phash, _)| phash),
+        salt3: dpk.is_some().then_some(salt3),
+        dpk,
     }
-    println!();
+}
 
-    println!("{dpk}");
-    for b in salt3 {
-        print!("{:02x}", b);
+// Runs the hash output through the verification code path, so any mismatch between the two (e.g.
+// in how the params are used) is caught before the output is stored anywhere
+fn self_verify(password: &[u8], output: &HashOutput, params: &DerivationParams) {
+    let (Some(phash), Some(salt2)) = (&output.phash, &output.salt2) else {
+        fail(
+            EXIT_USAGE,
+            "--verify-after needs the digest stage to verify against",
+        );
+    };
+    let matches = match (&output.dpk, &output.salt3) {
+        (Some(dpk), Some(salt3)) => {
+            verify_password(password, &output.salt1, salt2, salt3, phash, None, params).as_ref()
+                == Some(dpk)
+        }
+        _ => check_password(password, &output.salt1, salt2, phash, None, params).is_some(),
+    };
+    // Either phash alone is enough to pass verification, so each one is checked on its own
+    let matches = matches
+        && output.phash2.as_ref().is_none_or(|(digest, phash)| {
+            let params = DerivationParams {
+                digest: *digest,
+                ..*params
+            };
+            check_password(password, &output.salt1, salt2, phash, None, &params).is_some()
+        });
+    if !matches {
+        fail(
+            EXIT_SOFTWARE,
+            "Self-verification failed: the hash output doesn't verify against the password",
+        );
     }
-    println!();
 }
 
-fn verify_password(password: &Vec<u8>, salt1: &[u8], salt2: &[u8], salt3: &[u8], phash: &str) {
-    let mk = generate_hash_mk(password, salt1);
-    let hash_mk = generate_hash_mk(mk.as_bytes(), salt2);
-    if hash_mk == phash {
-        println!("Match");
-        let dpk = derive_dpk(password, salt3);
-        println!("{}", dpk);
+fn print_hash_output(output: &HashOutput, params: &DerivationParams, format: &OutputFormat) {
+    if format.binary {
+        let bundle = Bundle {
+            mk_params: params.mk,
+            dpk_params: params.dpk,
+            salt1: output.salt1.to_vec(),
+            mk: output.mk.as_deref().map(|mk| decode_hash(mk)),
+            phash: output.phash.as_deref().map(decode_hash),
+            phash2: output
+                .phash2
+                .as_ref()
+                .map(|(digest, phash)| (*digest, decode_hash(phash))),
+            salt2: output.salt2.map(|salt| salt.to_vec()),
+            dpk: output.dpk.as_deref().map(|dpk| decode_hash(dpk)),
+            salt3: output.salt3.map(|salt| salt.to_vec()),
+            dpk_source: params.dpk_source,
+            digest: params.digest,
+            metadata: format.embed_metadata.then(Metadata::now),
+            signature: None,
+        };
+        #[cfg(feature = "sign")]
+        let bundle = sign_bundle(bundle, format.sign_key.as_ref());
+        if let Err(e) = io::stdout().write_all(&bundle.encode()) {
+            fail(EXIT_IO, format!("failed to write the bundle: {}", e));
+        }
+        return;
+    }
+
+    // Each field is wiped once printed, since the MK and the DPK are among them
+    let salt =
+        |salt: &[u8]| Zeroizing::new(encode_salt(salt, format.salt_encoding, format.hex_case));
+    let fields = [
+        ("salt1", Some(salt(&output.salt1))),
+        ("mk", output.mk.clone()),
+        // Both phashes are labeled with their digest when there are two
+        (
+            match output.phash2 {
+                Some(_) => phash_label(params.digest),
+                None => "phash",
+            },
+            output.phash.clone().map(Zeroizing::new),
+        ),
+        (
+            output
+                .phash2
+                .as_ref()
+                .map_or("phash", |(d, _)| phash_label(*d)),
+            output
+                .phash2
+                .as_ref()
+                .map(|(_, phash)| Zeroizing::new(phash.clone())),
+        ),
+        ("salt2", output.salt2.map(|s| salt(&s))),
+        (
+            "dpk",
+            output
+                .dpk
+                .as_deref()
+                .filter(|_| !format.dpk_as.is_binary())
+                .map(|dpk| {
+                    let dpk = format_dpk(dpk, format.dpk_as);
+                    Zeroizing::new(
+                        std::str::from_utf8(&dpk)
+                            .expect("text formats are UTF-8")
+                            .to_string(),
+                    )
+                }),
+        ),
+        ("salt3", output.salt3.map(|s| salt(&s))),
+    ];
+    let fields = fields
+        .into_iter()
+        .filter_map(|(label, value)| Some((label, value?)));
+    if format.json {
+        let object: serde_json::Map<_, _> = fields
+            .map(|(label, value)| (label.to_string(), value.as_str().into()))
+            .collect();
+        print_json(serde_json::Value::Object(object));
+    } else if let Some(dir) = &format.out_dir {
+        let dpk = output.dpk.as_deref().filter(|_| format.dpk_as.is_binary());
+        let binary_dpk = dpk.map(|dpk| ("dpk", format_dpk(dpk, format.dpk_as)));
+        let fields =
+            fields.map(|(label, value)| (label, Zeroizing::new(value.as_bytes().to_vec())));
+        for (label, value) in fields.chain(binary_dpk) {
+            let path = dir.join(label);
+            if let Err(e) = write_field(&path, &value, format.overwrite) {
+                fail(
+                    EXIT_IO,
+                    format!("failed to write {}: {}", path.display(), e),
+                );
+            }
+        }
     } else {
-        println!("Mismatch");
+        let lines: Zeroizing<Vec<String>> = Zeroizing::new(
+            fields
+                .map(|(label, value)| match &format.env_prefix {
+                    Some(prefix) => format!(
+                        "export {prefix}_{}={}",
+                        label.to_uppercase(),
+                        shell_quote(&value)
+                    ),
+                    // Grouping is for humans, so it is left out of the other outputs
+                    None => {
+                        let value = match label {
+                            "salt1" | "salt2" | "salt3" => group_salt(&value, format.salt_group),
+                            _ => value.to_string(),
+                        };
+                        if format.labeled {
+                            format!("{label}={value}")
+                        } else {
+                            value
+                        }
+                    }
+                })
+                .collect(),
+        );
+        print_output(&Zeroizing::new(lines.join("\n")));
     }
 }
 
-fn get_salt(salt: String) -> [u8; 16] {
-    let mut s = [0u8; 16];
-    for i in 0..16 {
-        let byte = u8::from_str_radix(&salt[i * 2..i * 2 + 2], 16)
-            .map_err(|_| "invalid hex")
-            .unwrap();
-        s[i] = byte;
+// Runs the derivation `repeat` times (to amortize the process startup when measuring it) and
+// returns the result of the last run
+fn timed<T>(repeat: u32, time: bool, derive: impl Fn() -> T) -> T {
+    let start = Instant::now();
+    let mut result = derive();
+    for _ in 1..repeat {
+        result = derive();
+    }
+    if time {
+        let average = start.elapsed() / repeat;
+        info(format!(
+            "time: {:.3} ms per derivation (average over {repeat} run(s))",
+            average.as_secs_f64() * 1000.0
+        ));
     }
 
-    s
+    result
 }
 
-fn readpw() -> Result<Vec<u8>, io::Error> {
-    let mut buf = Vec::new();
-    io::stdin().read_to_end(&mut buf)?;
+const FIELDS: [&str; 10] = [
+    "salt1",
+    "mk",
+    "phash",
+    "phash_yescrypt",
+    "phash_sha256",
+    "phash_sha512",
+    "phash_blake3",
+    "salt2",
+    "dpk",
+    "salt3",
+];
 
-    // Remove trailing newline(s)
-    while matches!(buf.last(), Some(b'\n' | b'\r')) {
-        buf.pop();
+fn phash_label(digest: DigestAlgo) -> &'static str {
+    match digest {
+        DigestAlgo::Yescrypt => "phash_yescrypt",
+        DigestAlgo::Sha256 => "phash_sha256",
+        DigestAlgo::Sha512 => "phash_sha512",
+        DigestAlgo::Blake3 => "phash_blake3",
     }
-
-    Ok(buf)
 }
 
-fn generate_hash_mk(password: &[u8], salt: &[u8]) -> String {
-    let params = yescrypt::Params::new_with_all_params(Mode::default(), 2048, 8, 1, 0, 0).unwrap();
-    let mk_or_hash = Yescrypt.hash_password_with_params(password, &salt, params);
-    format!("{}", mk_or_hash.unwrap().fields().last().unwrap().as_str())
+// Fail before doing any derivation rather than after
+fn prepare_out_dir(dir: &Path, overwrite: bool) {
+    if let Err(e) = retry_io(|| fs::create_dir_all(dir)) {
+        fail(
+            EXIT_IO,
+            format!("failed to create {}: {}", dir.display(), e),
+        );
+    }
+    if !overwrite {
+        if let Some(path) = FIELDS.iter().map(|f| dir.join(f)).find(|p| p.exists()) {
+            fail(
+                EXIT_IO,
+                format!(
+                    "{} already exists; pass --overwrite to replace it",
+                    path.display()
+                ),
+            );
+        }
+    }
 }
 
-fn derive_dpk(password: &[u8], salt: &[u8]) -> String {
-    let params =
-        yescrypt::Params::new_with_all_params(Mode::default(), 32768, 32, 1, 0, 0).unwrap();
-    let hash = Yescrypt.hash_password_with_params(&password, salt, params);
-    format!("{}", hash.unwrap().fields().last().unwrap().as_str())
+fn write_field(path: &Path, value: &[u8], overwrite: bool) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true);
+    if overwrite {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        let file = retry_io(|| options.open(path))?;
+        // The mode only applies to new files
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        (&file).write_all(value)
+    }
+    #[cfg(not(unix))]
+    retry_io(|| options.open(path))?.write_all(value)
 }
 
-fn generate_salt() -> [u8; 16] {
-    let mut salt = [0u8; 16];
-    match OsRng.try_fill_bytes(&mut salt) {
-        Ok(salt) => salt,
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            std::process::exit(2);
-        }
+// Lets an operator confirm which params were read from the phash or the bundle, e.g. when a bundle
+// made by another version doesn't verify
+fn report_verify_params(stages: &[&str], params: &DerivationParams) {
+    let yescrypt = |params: &StageParams, salt: &str| {
+        format!(
+            "yescrypt, mode {:x}, N {}, r {}, p {}, t {}, {} ({SALT_LEN} bytes)",
+            u32::from(params.mode),
+            params.n,
+            params.r,
+            params.p,
+            params.t,
+            salt
+        )
     };
-    salt
+    for &stage in stages {
+        let used = match stage {
+            "mk" => yescrypt(&params.mk, "salt 1"),
+            "digest" if params.digest == DigestAlgo::Yescrypt => yescrypt(&params.mk, "salt 2"),
+            "digest" => {
+                let name = params.digest.to_possible_value().unwrap();
+                format!("{} keyed with salt 2 ({SALT_LEN} bytes)", name.get_name())
+            }
+            _ => match params.dpk_source {
+                DpkSource::Mk => format!("{}, from the MK", yescrypt(&params.dpk, "salt 3")),
+                DpkSource::Password => {
+                    format!("{}, from the password", yescrypt(&params.dpk, "salt 3"))
+                }
+            },
+        };
+        info(format!("params: {stage}: {used}"));
+    }
+}
+
+fn params_json(params: &DerivationParams) -> serde_json::Value {
+    let dpk_source = params.dpk_source.to_possible_value().unwrap();
+    serde_json::json!({
+        "mk": stage_params_json(&params.mk),
+        "dpk": stage_params_json(&params.dpk),
+        "dpk_source": dpk_source.get_name(),
+        "digest": params.digest.to_possible_value().unwrap().get_name(),
+    })
+}
+
+fn stage_params_json(params: &StageParams) -> serde_json::Value {
+    serde_json::json!({
+        "kdf": "yescrypt",
+        "salt_len": SALT_LEN,
+        "mode": u32::from(params.mode),
+        "n": params.n,
+        "r": params.r,
+        "p": params.p,
+        "t": params.t,
+    })
+}
+
+// Prints the final output of a run, without its trailing newline under --no-trailing-newline
+fn print_output(text: &str) {
+    if NO_TRAILING_NEWLINE.load(Ordering::Relaxed) {
+        print!("{text}");
+    } else {
+        println!("{text}");
+    }
+}
+
+fn print_json(mut value: serde_json::Value) {
+    if let Some(object) = value.as_object_mut() {
+        object.insert("version".to_string(), JSON_FORMAT_VERSION.into());
+    }
+    let text = Zeroizing::new(if JSON_PRETTY.load(Ordering::Relaxed) {
+        serde_json::to_string_pretty(&value).unwrap()
+    } else {
+        value.to_string()
+    });
+    print_output(&text);
+    wipe_json(&mut value);
+}
+
+// The objects may hold the MK or the DPK, and serde_json doesn't wipe its strings
+fn wipe_json(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::String(s) => s.zeroize(),
+        serde_json::Value::Array(values) => values.iter_mut().for_each(wipe_json),
+        serde_json::Value::Object(object) => object.values_mut().for_each(wipe_json),
+        _ => {}
+    }
+}
+
+fn format_dpk(dpk: &str, format: DpkFormat) -> Zeroizing<Vec<u8>> {
+    let bytes = Zeroizing::new(decode_hash(dpk));
+    let key_len = match format {
+        DpkFormat::Aes256 | DpkFormat::Chacha20 => Some(32),
+        _ => None,
+    };
+    if key_len.is_some_and(|len| len != bytes.len()) {
+        fail(
+            EXIT_USAGE,
+            format!(
+                "the DPK is {} bytes long, which doesn't fit the requested key",
+                bytes.len()
+            ),
+        );
+    }
+    Zeroizing::new(match format {
+        DpkFormat::Base64 => dpk.as_bytes().to_vec(),
+        DpkFormat::Hex => {
+            let mut hex = Vec::with_capacity(bytes.len() * 2);
+            for b in bytes.iter() {
+                hex.extend_from_slice(format!("{:02x}", b).as_bytes());
+            }
+            hex
+        }
+        DpkFormat::Raw | DpkFormat::Aes256 | DpkFormat::Chacha20 => bytes.to_vec(),
+        DpkFormat::Pem => {
+            let base64 = Zeroizing::new(Base64::encode_string(&bytes));
+            let base64 = base64.as_str();
+            format!("-----BEGIN MKDF DPK-----\n{base64}\n-----END MKDF DPK-----\n").into_bytes()
+        }
+    })
+}
+
+// Single quotes keep the shell from expanding anything, e.g. the $ of a PHC string
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+fn encode_salt(salt: &[u8], encoding: SaltEncoding, case: HexCase) -> String {
+    match encoding {
+        SaltEncoding::Hex => salt
+            .iter()
+            .map(|b| match case {
+                HexCase::Lower => format!("{:02x}", b),
+                HexCase::Upper => format!("{:02X}", b),
+            })
+            .collect(),
+        SaltEncoding::Base64url => Base64UrlUnpadded::encode_string(salt),
+    }
+}
+
+fn group_salt(salt: &str, group: Option<u16>) -> String {
+    let Some(group) = group else {
+        return salt.to_string();
+    };
+    let chars: Vec<char> = salt.chars().collect();
+    let groups: Vec<String> = chars
+        .chunks(usize::from(group))
+        .map(|chunk| chunk.iter().collect())
+        .collect();
+    groups.join(" ")
+}
+
+// Surrounding whitespace is likely a paste artefact, and single spaces separate the groups of
+// --salt-group, so both are dropped; any other whitespace within the salt is an error
+fn parse_salt_arg(s: &str) -> Result<String, String> {
+    let s = s.trim_ascii();
+    if s.contains(|c: char| c.is_whitespace() && c != ' ') || s.contains("  ") {
+        return Err(format!("unexpected whitespace within the salt {s:?}"));
+    }
+    Ok(s.replace(' ', ""))
+}
+
+fn verify_password(
+    password: &[u8],
+    salt1: &[u8],
+    salt2: &[u8],
+    salt3: &[u8],
+    phash: &str,
+    also: Option<(DigestAlgo, &str)>,
+    params: &DerivationParams,
+) -> Option<Zeroizing<String>> {
+    check_password(password, salt1, salt2, phash, also, params)
+        .map(|mk| expect_kdf(derive_dpk(password, &mk, salt3, params)))
+}
+
+// Returns the MK if the password matches the phash, since the DPK may be derived from it
+fn check_password(
+    password: &[u8],
+    salt1: &[u8],
+    salt2: &[u8],
+    phash: &str,
+    also: Option<(DigestAlgo, &str)>,
+    params: &DerivationParams,
+) -> Option<Zeroizing<String>> {
+    let mk = Zeroizing::new(expect_kdf(generate_hash_mk(password, salt1, &params.mk)));
+    expect_kdf(check_mk(&mk, salt2, phash, also, params)).then_some(mk)
+}
+
+// `also` is a second phash made with another digest; both are always checked, so that the timing
+// doesn't tell which one matched
+fn check_mk(
+    mk: &str,
+    salt2: &[u8],
+    phash: &str,
+    also: Option<(DigestAlgo, &str)>,
+    params: &DerivationParams,
+) -> Result<bool, String> {
+    let matches = |phash: &str, params: &DerivationParams| {
+        let hash_mk = digest_mk(mk, salt2, params, phash.starts_with("$y$"))?;
+        Ok::<_, String>(ct_eq(hash_mk.as_bytes(), phash.as_bytes()))
+    };
+    let primary = matches(phash, params)?;
+    let Some((digest, also)) = also else {
+        return Ok(primary);
+    };
+    let second = matches(also, &DerivationParams { digest, ..*params })?;
+    if verbose() && (primary || second) {
+        let digest = if primary { params.digest } else { digest };
+        let name = digest.to_possible_value().unwrap();
+        info(format!(
+            "digest: the password matches the {} phash",
+            name.get_name()
+        ));
+    }
+
+    Ok(primary || second)
+}
+
+// The fast digests are keyed with salt 2 so that they can't be precomputed for a given MK
+fn digest_mk(
+    mk: &str,
+    salt2: &[u8],
+    params: &DerivationParams,
+    phc: bool,
+) -> Result<String, String> {
+    fn hmac<D: Mac + KeyInit>(key: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut mac = <D as Mac>::new_from_slice(key).expect("HMAC takes keys of any size");
+        mac.update(data);
+        mac.finalize().into_bytes().to_vec()
+    }
+    let mk = mk.as_bytes();
+    match params.digest {
+        DigestAlgo::Yescrypt if phc => generate_phc(mk, salt2, &params.mk),
+        DigestAlgo::Yescrypt => generate_hash_mk(mk, salt2, &params.mk),
+        DigestAlgo::Sha256 => Ok(encode_hash(&hmac::<Hmac<Sha256>>(salt2, mk))),
+        DigestAlgo::Sha512 => Ok(encode_hash(&hmac::<Hmac<Sha512>>(salt2, mk))),
+        DigestAlgo::Blake3 => {
+            let key = blake3::derive_key("MKDF 2026 phash key", salt2);
+            Ok(encode_hash(blake3::keyed_hash(&key, mk).as_bytes()))
+        }
+    }
+}
+
+fn parse_env_prefix(s: &str) -> Result<String, String> {
+    let mut chars = s.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        Ok(s.to_string())
+    } else {
+        Err(format!("{s} is not a valid shell variable name"))
+    }
+}
+
+fn parse_delimiter(s: &str) -> Result<u8, String> {
+    match s {
+        "\\0" => Ok(0),
+        "\\n" => Ok(b'\n'),
+        "\\r" => Ok(b'\r'),
+        "\\t" => Ok(b'\t'),
+        _ if s.starts_with("0x") && s.len() > 2 => {
+            u8::from_str_radix(&s[2..], 16).map_err(|_| format!("invalid byte: {s}"))
+        }
+        _ if s.len() == 1 => Ok(s.as_bytes()[0]),
+        _ => Err(format!(
+            "expected a single character, an escape or 0xNN, got {s}"
+        )),
+    }
+}
+
+fn parse_yescrypt_flags(s: &str) -> Result<Mode, String> {
+    let hex = s.strip_prefix("0x").unwrap_or(s);
+    let flags = u32::from_str_radix(hex, 16).map_err(|_| format!("invalid hex: {s}"))?;
+    Mode::try_from(flags)
+        .map_err(|_| format!("unsupported flags {s}; expected 0 (classic), 1 (worm) or 2f (rw)"))
+}
+
+fn parse_min_params(s: &str) -> Result<(u64, u32, u32), String> {
+    let parts: Vec<&str> = s.split(',').collect();
+    match parts[..] {
+        [n, r, p] => Ok((
+            n.parse().map_err(|_| format!("invalid N: {n}"))?,
+            r.parse().map_err(|_| format!("invalid r: {r}"))?,
+            p.parse().map_err(|_| format!("invalid p: {p}"))?,
+        )),
+        _ => Err("expected N,R,P".to_string()),
+    }
+}
+
+// A PHC phash carries its own params, which an attacker who controls the storage could lower; a
+// bare phash is checked against the MK params passed on the command line since those are used
+fn check_min_params(phash: &str, mk: &StageParams, min: (u64, u32, u32)) {
+    let params = if phash.starts_with("$y$") {
+        match phc_params(phash) {
+            Some(params) => (params.n(), params.r(), params.p()),
+            None => {
+                fail(EXIT_USAGE, "The phash is not a valid yescrypt PHC string");
+            }
+        }
+    } else {
+        (mk.n, mk.r, mk.p)
+    };
+    validate_min_params("phash", params, min).unwrap_or_else(|e| fail(EXIT_DOWNGRADE, e));
+}
+
+fn validate_format_version(version: u8, min: u8) -> Result<(), String> {
+    if version < min {
+        return Err(format!(
+            "The bundle is in format version {version}, below --min-format-version {min}; \
+             refusing a possible downgrade"
+        ));
+    }
+    Ok(())
+}
+
+fn validate_min_params(
+    what: &str,
+    (n, r, p): (u64, u32, u32),
+    min: (u64, u32, u32),
+) -> Result<(), String> {
+    if n < min.0 || r < min.1 || p < min.2 {
+        return Err(format!(
+            "The {what} params (N={n}, r={r}, p={p}) are below the required minimum \
+             (N={}, r={}, p={}); refusing a possible downgrade",
+            min.0, min.1, min.2
+        ));
+    }
+    Ok(())
+}
+
+// A PHC phash records the params it was made with, so they are used for the MK stage instead of
+// the MK params passed on the command line; a bare phash leaves them as they are
+fn phash_mk_params(phash: &str, mk: &StageParams, force: bool) -> StageParams {
+    parse_phash_mk_params(phash, mk, force).unwrap_or_else(|e| fail(EXIT_USAGE, e))
+}
+
+fn parse_phash_mk_params(
+    phash: &str,
+    mk: &StageParams,
+    force: bool,
+) -> Result<StageParams, String> {
+    if !phash.starts_with("$y$") {
+        return Ok(*mk);
+    }
+    let Some(params) = phc_params(phash) else {
+        return Err("The phash is not a valid yescrypt PHC string".to_string());
+    };
+    let stage = StageParams {
+        n: params.n(),
+        r: params.r(),
+        p: params.p(),
+        ..*mk
+    };
+    check_params("phash", &stage, force)?;
+    // The mode and t aren't exposed by `Params`, so check them by comparing the whole thing
+    if stage.yescrypt().ok() != Some(params) {
+        return Err(
+            "The phash was made with other yescrypt flags or t; pass the same --yescrypt-flags and --t"
+                .to_string(),
+        );
+    }
+
+    Ok(stage)
+}
+
+fn inspect_phc(phash: &str, case: HexCase, json: bool) {
+    let Some(hash) = PasswordHashRef::new(phash)
+        .ok()
+        .filter(|hash| hash.id() == "y")
+    else {
+        fail(EXIT_USAGE, "The phash is not a valid yescrypt PHC string");
+    };
+    let fields: Vec<&str> = hash.fields().map(|field| field.as_str()).collect();
+    let [params_field, salt, digest] = fields[..] else {
+        fail(
+            EXIT_USAGE,
+            format!("Expected 3 fields after the id, got {}", fields.len()),
+        );
+    };
+    let params: Params = params_field
+        .parse()
+        .unwrap_or_else(|e| fail(EXIT_USAGE, format!("Invalid params {params_field}: {e}")));
+    // Salts made by MKDF are raw bytes, but other implementations may use a string as is
+    let salt_hex = Base64ShaCrypt::decode_vec(salt)
+        .ok()
+        .map(|salt| encode_salt(&salt, SaltEncoding::Hex, case));
+
+    let memory = 128 * params.n() * u64::from(params.r()) * u64::from(params.p());
+    let mut fields: Vec<(&str, serde_json::Value)> = vec![
+        ("id", hash.id().into()),
+        ("params", params_field.into()),
+        ("n", params.n().into()),
+        ("r", params.r().into()),
+        ("p", params.p().into()),
+        ("memory", memory.into()),
+        ("salt", salt.into()),
+    ];
+    fields.extend(salt_hex.map(|hex| ("salt_hex", hex.into())));
+    fields.push(("hash", digest.into()));
+
+    if json {
+        let object: serde_json::Map<_, _> = fields
+            .into_iter()
+            .map(|(label, value)| (label.to_string(), value))
+            .collect();
+        print_json(serde_json::Value::Object(object));
+    } else {
+        let lines: Vec<String> = fields
+            .into_iter()
+            .map(|(label, value)| match value {
+                serde_json::Value::String(value) => format!("{label}={value}"),
+                value => format!("{label}={value}"),
+            })
+            .collect();
+        print_output(&lines.join("\n"));
+    }
+}
+
+fn phc_params(phash: &str) -> Option<Params> {
+    let phash = PasswordHashRef::new(phash).ok()?;
+    phash.fields().next()?.as_str().parse().ok()
+}
+
+// Salts generated by MKDF come from the OS CSPRNG, so an all-zero salt is a mistake or an attempt
+// to defeat the per-user uniqueness they provide
+fn check_salt_entropy(salts: &[&[u8]], force: bool) {
+    if let Err(e) = validate_salt_entropy(salts, force) {
+        fail(EXIT_USAGE, e);
+    }
+    for (i, salt) in salts.iter().enumerate() {
+        let mut distinct = salt.to_vec();
+        distinct.sort_unstable();
+        distinct.dedup();
+        if distinct != [0] && distinct.len() < 4 {
+            warn(format!(
+                "salt {} looks like it has very little entropy",
+                i + 1
+            ));
+        }
+    }
+}
+
+// Only the errors, since the warnings of check_salt_entropy go to STDERR
+fn validate_salt_entropy(salts: &[&[u8]], force: bool) -> Result<(), String> {
+    for (i, salt) in salts.iter().enumerate() {
+        if !force && salt.iter().all(|&b| b == 0) {
+            return Err(format!(
+                "Salt {} is all zeros; pass --force to use it anyway",
+                i + 1
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Fails fast on a phash that can't possibly match, rather than running the KDF to report a Mismatch
+fn check_phash(phash: &str, digest: DigestAlgo) {
+    if let Err(e) = validate_phash(phash, digest) {
+        fail(EXIT_USAGE, e);
+    }
+}
+
+fn validate_phash(phash: &str, digest: DigestAlgo) -> Result<(), String> {
+    if phash.starts_with("$y$") && digest != DigestAlgo::Yescrypt {
+        Err("A PHC phash is a yescrypt digest; it can't be checked with --digest-algo".to_string())
+    } else if phash.starts_with("$y$") {
+        let fields = PasswordHashRef::new(phash).ok().map(|phash| {
+            phash
+                .fields()
+                .map(|field| field.as_str())
+                .collect::<Vec<_>>()
+        });
+        let valid = match fields.as_deref() {
+            Some([params, salt, hash]) => {
+                params.parse::<Params>().is_ok()
+                    && Base64ShaCrypt::decode_vec(salt).is_ok()
+                    && Base64ShaCrypt::decode_vec(hash).is_ok_and(|hash| hash.len() == HASH_LEN)
+            }
+            _ => false,
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err("The phash is not a valid yescrypt PHC string".to_string())
+        }
+    } else if !Base64ShaCrypt::decode_vec(phash).is_ok_and(|hash| hash.len() == digest.len()) {
+        Err(format!(
+            "The phash must be a yescrypt PHC string ($y$...) or a bare {}-character hash",
+            Base64ShaCrypt::encoded_len(&vec![0; digest.len()])
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+// A PHC phash also carries salt 2, so a phash pasted along with the wrong --s2 can be told apart
+// from a wrong password
+fn check_phc_salt(phash: &str, salt2: &[u8]) {
+    if let Err(e) = validate_phc_salt(phash, salt2) {
+        fail(EXIT_USAGE, e);
+    }
+}
+
+fn validate_phc_salt(phash: &str, salt2: &[u8]) -> Result<(), String> {
+    if !phash.starts_with("$y$") {
+        return Ok(());
+    }
+    let salt = PasswordHashRef::new(phash)
+        .ok()
+        .and_then(|phash| phash.fields().nth(1))
+        .and_then(|salt| Base64ShaCrypt::decode_vec(salt.as_str()).ok());
+    match salt {
+        Some(salt) if salt == salt2 => Ok(()),
+        Some(_) => Err("Salt mismatch between --s2 and --phash".to_string()),
+        None => Err("The phash is not a valid yescrypt PHC string".to_string()),
+    }
+}
+
+fn check_salt_lengths(salts: &[&str], encoding: SaltEncoding) {
+    let len = match encoding {
+        SaltEncoding::Hex => SALT_LEN * 2,
+        SaltEncoding::Base64url => Base64UrlUnpadded::encoded_len(&[0u8; SALT_LEN]),
+    };
+    if salts.iter().any(|salt| salt.len() != len) {
+        fail(
+            EXIT_USAGE,
+            format!("The salts must be {len} characters long ({SALT_LEN} bytes long)"),
+        );
+    }
+}
+
+fn get_salt(salt: String, encoding: SaltEncoding) -> [u8; SALT_LEN] {
+    decode_salt(&salt, encoding).unwrap_or_else(|e| fail(EXIT_USAGE, e))
+}
+
+fn decode_salt(salt: &str, encoding: SaltEncoding) -> Result<[u8; SALT_LEN], String> {
+    if encoding == SaltEncoding::Base64url {
+        let mut s = [0u8; SALT_LEN];
+        Base64UrlUnpadded::decode(salt, &mut s)
+            .map_err(|_| format!("invalid base64url salt: {salt}"))?;
+        return Ok(s);
+    }
+    decode_hex(salt).map_err(|e| format!("invalid hex salt {salt}: {e}"))
+}
+
+// Works on bytes rather than slicing the string, so odd lengths and non-ASCII input are errors
+// rather than panics
+fn decode_hex<const N: usize>(hex: &str) -> Result<[u8; N], String> {
+    let bytes = decode_hex_vec(hex)?;
+    bytes
+        .try_into()
+        .map_err(|bytes: Vec<u8>| format!("expected {} digits, got {}", N * 2, bytes.len() * 2))
+}
+
+fn decode_hex_vec(hex: &str) -> Result<Vec<u8>, String> {
+    let digits = hex.as_bytes();
+    if !hex.is_ascii() {
+        return Err("non-ASCII characters".to_string());
+    }
+    if !digits.len().is_multiple_of(2) {
+        return Err(format!("odd number of digits ({})", digits.len()));
+    }
+    let digit = |d: u8| {
+        char::from(d)
+            .to_digit(16)
+            .ok_or(format!("invalid hex digit {:?}", char::from(d)))
+    };
+    digits
+        .chunks_exact(2)
+        .map(|pair| Ok((digit(pair[0])? << 4 | digit(pair[1])?) as u8))
+        .collect()
+}
+
+// Only tells whether the password opens each bundle: the DPKs aren't derived, and a bundle that
+// can't be read is reported rather than aborting the whole run. check_bundle is the password
+// check with the limits of the command line. Returns whether any bundle matched
+fn verify_bundle_dir(
+    dir: &Path,
+    parallel: bool,
+    json: bool,
+    check_bundle: impl Fn(&[u8]) -> Result<bool, String> + Sync,
+) -> bool {
+    use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+    let entries = retry_io(|| fs::read_dir(dir))
+        .and_then(|entries| entries.collect::<io::Result<Vec<_>>>())
+        .unwrap_or_else(|e| fail(EXIT_IO, format!("failed to read {}: {}", dir.display(), e)));
+    let mut paths: Vec<PathBuf> = entries
+        .iter()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .collect();
+    paths.sort();
+
+    let check = |path: &PathBuf| -> (String, Result<bool, String>) {
+        let data = retry_io(|| fs::read(path)).map(Zeroizing::new);
+        let result = data.map_err(|e| e.to_string());
+        let result = result.and_then(|data| check_bundle(&data));
+        (path.display().to_string(), result)
+    };
+    // Collecting keeps the results in the order of the paths either way
+    let results: Vec<(String, Result<bool, String>)> = if parallel {
+        paths.par_iter().map(check).collect()
+    } else {
+        paths.iter().map(check).collect()
+    };
+    let matched = results.iter().filter(|(_, r)| r == &Ok(true)).count();
+
+    if json {
+        let bundles: Vec<_> = results
+            .iter()
+            .map(|(file, result)| match result {
+                Ok(matches) => serde_json::json!({"file": file, "match": matches}),
+                Err(e) => serde_json::json!({"file": file, "error": e}),
+            })
+            .collect();
+        let report = serde_json::json!({
+            "bundles": bundles,
+            "matched": matched,
+            "total": results.len(),
+        });
+        print_json(report);
+    } else {
+        let mut lines: Vec<String> = results
+            .iter()
+            .map(|(file, result)| match result {
+                Ok(true) => format!("{file}: Match"),
+                Ok(false) => format!("{file}: Mismatch"),
+                Err(e) => format!("{file}: invalid bundle ({e})"),
+            })
+            .collect();
+        lines.push(format!("{} of {} bundles match", matched, results.len()));
+        print_output(&lines.join("\n"));
+    }
+
+    matched > 0
+}
+
+// Checks the password against an encoded bundle, reporting a bundle that can't be used as an error
+// rather than exiting, for the callers that go through many of them. The bundles may come from
+// anyone, so their params are bounded by max_memory (in MiB) as well as held to the floor and to
+// min_params (--min-params)
+fn check_bundle(
+    password: &[u8],
+    data: &[u8],
+    min_params: Option<(u64, u32, u32)>,
+    min_version: Option<u8>,
+    max_memory: u64,
+    force: bool,
+) -> Result<bool, String> {
+    // Checked ahead of decoding, which refuses the formats it can't read as invalid
+    if let (Some(min), Ok(version)) = (min_version, bundle::version(data)) {
+        validate_format_version(version, min)?;
+    }
+    let mut bundle = Bundle::decode(data)?;
+    // Only the phash is checked, so the MK and DPK the bundle may hold are wiped right away
+    drop((
+        bundle.mk.take().map(Zeroizing::new),
+        bundle.dpk.take().map(Zeroizing::new),
+    ));
+    check_bundle_params(&bundle, force)?;
+    if let Some(min) = min_params {
+        let StageParams { n, r, p, .. } = bundle.mk_params;
+        validate_min_params("bundle MK", (n, r, p), min)?;
+    }
+    for (stage, params) in [("MK", &bundle.mk_params), ("DPK", &bundle.dpk_params)] {
+        let memory = memory_cost(params) >> 20;
+        if memory > max_memory {
+            return Err(format!(
+                "the bundle {stage} stage takes {memory} MiB, more than --max-bundle-memory ({max_memory} MiB)"
+            ));
+        }
+    }
+    let (Some(phash), Some(salt2)) = (bundle.phash, bundle.salt2) else {
+        return Err("the bundle holds no phash".to_string());
+    };
+    if !force && (bundle.salt1.iter().all(|&b| b == 0) || salt2.iter().all(|&b| b == 0)) {
+        return Err("all-zero salt".to_string());
+    }
+    let params = DerivationParams {
+        mk: bundle.mk_params,
+        dpk: bundle.dpk_params,
+        dpk_source: bundle.dpk_source,
+        digest: bundle.digest,
+    };
+    let phash = encode_hash(&phash);
+    let phash2 = bundle
+        .phash2
+        .map(|(digest, phash)| (digest, encode_hash(&phash)));
+    let also = phash2
+        .as_ref()
+        .map(|(digest, phash)| (*digest, phash.as_str()));
+    let mk = Zeroizing::new(generate_hash_mk(password, &bundle.salt1, &params.mk)?);
+    check_mk(&mk, &salt2, &phash, also, &params)
+}
+
+// Rows hold the password first since it is the only field that may contain commas; blank lines and
+// lines starting with # are skipped. Returns whether every row matched
+fn compat_check(path: &Path, mk: &StageParams, force: bool, json: bool) -> bool {
+    let data = retry_io(|| fs::read_to_string(path))
+        .unwrap_or_else(|e| fail(EXIT_IO, format!("failed to read {}: {}", path.display(), e)));
+    let mut rows = 0;
+    let mut mismatches = Vec::new();
+    for (i, line) in data.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.rsplitn(3, ',');
+        let (Some(expected), Some(salt), Some(password)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            fail(
+                EXIT_USAGE,
+                format!("line {}: expected password,salt,expected_hash", i + 1),
+            );
+        };
+        let salt = decode_hex_vec(salt)
+            .unwrap_or_else(|e| fail(EXIT_USAGE, format!("line {}: invalid salt: {}", i + 1, e)));
+        let params = phash_mk_params(expected, mk, force);
+        let got = if expected.starts_with("$y$") {
+            generate_phc(password.as_bytes(), &salt, &params)
+        } else {
+            generate_hash_mk(password.as_bytes(), &salt, &params)
+        };
+        let got = expect_kdf(got);
+        rows += 1;
+        if got != expected {
+            mismatches.push((i + 1, expected, got));
+        }
+    }
+
+    if json {
+        let mismatches: Vec<_> = mismatches
+            .iter()
+            .map(|(line, expected, got)| {
+                serde_json::json!({"line": line, "expected": expected, "got": got})
+            })
+            .collect();
+        print_json(serde_json::json!({"rows": rows, "mismatches": mismatches}));
+    } else {
+        let mut lines: Vec<String> = mismatches
+            .iter()
+            .map(|(line, expected, got)| format!("line {line}: expected {expected}, got {got}"))
+            .collect();
+        lines.push(format!(
+            "{} of {} rows match",
+            rows - mismatches.len(),
+            rows
+        ));
+        print_output(&lines.join("\n"));
+    }
+
+    mismatches.is_empty()
+}
+
+// Welch's t-statistic above which the gap between the timings is reported, as in TVLA leakage
+// assessments
+const TIMING_T_THRESHOLD: f64 = 4.5;
+
+// Only times the MK and digest stages: a match goes on to derive the DPK, which is expected to take
+// longer and reveals nothing that the result itself doesn't
+fn timing_test(password: &[u8], params: &DerivationParams, runs: u32, json: bool) -> bool {
+    let Some((&last, rest)) = password.split_last() else {
+        fail(EXIT_USAGE, "timing-test needs a non-empty password");
+    };
+    // All but one bit of the password, so it is wiped like the password
+    let wrong = Zeroizing::new([rest, &[last ^ 1]].concat());
+    let (salt1, salt2) = (generate_salt(), generate_salt());
+    let mk = Zeroizing::new(expect_kdf(generate_hash_mk(password, &salt1, &params.mk)));
+    let phash = expect_kdf(digest_mk(&mk, &salt2, params, false));
+
+    let time = |password: &[u8]| {
+        let start = Instant::now();
+        let matched = check_password(password, &salt1, &salt2, &phash, None, params).is_some();
+        (start.elapsed().as_secs_f64() * 1000.0, matched)
+    };
+    // Interleaved so that drifts of the machine's load affect both alike
+    let (mut correct, mut incorrect) = (Vec::new(), Vec::new());
+    for _ in 0..runs {
+        let ((ok_ms, ok), (bad_ms, bad)) = (time(password), time(&wrong));
+        if !ok || bad {
+            fail(
+                EXIT_SOFTWARE,
+                "timing-test: verification gave the wrong result",
+            );
+        }
+        correct.push(ok_ms);
+        incorrect.push(bad_ms);
+    }
+
+    let stats = |times: &[f64]| {
+        let n = times.len() as f64;
+        let mean = times.iter().sum::<f64>() / n;
+        let variance = times.iter().map(|t| (t - mean).powi(2)).sum::<f64>() / (n - 1.0);
+        (mean, variance.sqrt())
+    };
+    let ((ok_mean, ok_sd), (bad_mean, bad_sd)) = (stats(&correct), stats(&incorrect));
+    let n = f64::from(runs);
+    let error = (ok_sd.powi(2) / n + bad_sd.powi(2) / n).sqrt();
+    let t = if error > 0.0 {
+        (ok_mean - bad_mean).abs() / error
+    } else {
+        0.0
+    };
+    let suspicious = t > TIMING_T_THRESHOLD;
+
+    if json {
+        print_json(serde_json::json!({
+            "runs": runs,
+            "correct": {"mean_ms": ok_mean, "stddev_ms": ok_sd},
+            "incorrect": {"mean_ms": bad_mean, "stddev_ms": bad_sd},
+            "t": t,
+            "suspicious": suspicious,
+        }));
+    } else {
+        let verdict = if suspicious {
+            "SUSPICIOUS: the timings differ"
+        } else {
+            "no significant gap"
+        };
+        let lines = [
+            "timing-test is a diagnostic, not part of normal operation".to_string(),
+            format!("correct:   mean {ok_mean:.3} ms, stddev {ok_sd:.3} ms ({runs} runs)"),
+            format!("incorrect: mean {bad_mean:.3} ms, stddev {bad_sd:.3} ms ({runs} runs)"),
+            format!("t = {t:.2} (threshold {TIMING_T_THRESHOLD}): {verdict}"),
+        ];
+        print_output(&lines.join("\n"));
+    }
+
+    suspicious
+}
+
+fn extract_field(bundle: &Bundle, field: BundleField, args: &Args) {
+    let name = field.to_possible_value().unwrap().get_name().to_owned();
+    let salt = |salt: &[u8]| {
+        let salt = encode_salt(salt, args.salt_encoding, args.hex_case);
+        if args.json {
+            salt
+        } else {
+            group_salt(&salt, args.salt_group)
+        }
+    };
+    let value = match field {
+        BundleField::Salt1 => Some(salt(&bundle.salt1).into_bytes()),
+        BundleField::Mk => bundle.mk.as_deref().map(|mk| encode_hash(mk).into_bytes()),
+        BundleField::Phash => bundle.phash.as_deref().map(|h| encode_hash(h).into_bytes()),
+        BundleField::Salt2 => bundle.salt2.as_deref().map(|s| salt(s).into_bytes()),
+        BundleField::Dpk => {
+            if args.json && args.dpk_as.is_binary() {
+                fail(EXIT_USAGE, "--json can only print the DPK as base64 or hex");
+            }
+            let dpk = bundle
+                .dpk
+                .as_deref()
+                .map(|dpk| Zeroizing::new(encode_hash(dpk)));
+            dpk.map(|dpk| format_dpk(&dpk, args.dpk_as).to_vec())
+        }
+        BundleField::Salt3 => bundle.salt3.as_deref().map(|s| salt(s).into_bytes()),
+    }
+    .map(Zeroizing::new);
+    let Some(value) = value else {
+        let label = match field {
+            BundleField::Salt1 => "salt 1",
+            BundleField::Mk => "MK",
+            BundleField::Phash => "phash",
+            BundleField::Salt2 => "salt 2",
+            BundleField::Dpk => "DPK",
+            BundleField::Salt3 => "salt 3",
+        };
+        fail(EXIT_USAGE, format!("The bundle has no {label}"));
+    };
+
+    if field == BundleField::Dpk && args.dpk_as.is_binary() {
+        if let Err(e) = io::stdout().write_all(&value) {
+            fail(EXIT_IO, format!("failed to write the DPK: {}", e));
+        }
+        return;
+    }
+    let value = std::str::from_utf8(&value).expect("text formats are UTF-8");
+    if args.json {
+        print_json(serde_json::json!({ name: value }));
+    } else {
+        print_output(value);
+    }
+}
+
+// Prints the status of each field (same, changed, added or removed) and returns whether the bundles
+// are the same
+fn diff_bundles(old: &Bundle, new: &Bundle, json: bool) -> bool {
+    let status = |old: Option<&[u8]>, new: Option<&[u8]>| match (old, new) {
+        (Some(old), Some(new)) if old != new => "changed",
+        (Some(_), None) => "removed",
+        (None, Some(_)) => "added",
+        _ => "same",
+    };
+    let same_if = |same: bool| if same { "same" } else { "changed" };
+    let fields = [
+        ("mk_params", same_if(old.mk_params == new.mk_params)),
+        ("dpk_params", same_if(old.dpk_params == new.dpk_params)),
+        ("dpk_source", same_if(old.dpk_source == new.dpk_source)),
+        ("digest", same_if(old.digest == new.digest)),
+        ("salt1", same_if(old.salt1 == new.salt1)),
+        ("mk", status(old.mk.as_deref(), new.mk.as_deref())),
+        ("phash", status(old.phash.as_deref(), new.phash.as_deref())),
+        (
+            "phash2",
+            status(
+                old.phash2.as_ref().map(|(_, phash)| &phash[..]),
+                new.phash2.as_ref().map(|(_, phash)| &phash[..]),
+            ),
+        ),
+        ("salt2", status(old.salt2.as_deref(), new.salt2.as_deref())),
+        ("dpk", status(old.dpk.as_deref(), new.dpk.as_deref())),
+        ("salt3", status(old.salt3.as_deref(), new.salt3.as_deref())),
+        (
+            "metadata",
+            status(
+                old.metadata.as_ref().map(Metadata::encode).as_deref(),
+                new.metadata.as_ref().map(Metadata::encode).as_deref(),
+            ),
+        ),
+        (
+            "signature",
+            status(old.signature.as_deref(), new.signature.as_deref()),
+        ),
+    ];
+
+    if json {
+        let object: serde_json::Map<_, _> = fields
+            .iter()
+            .map(|&(field, status)| (field.to_string(), status.into()))
+            .collect();
+        print_json(serde_json::Value::Object(object));
+    } else {
+        let lines: Vec<String> = fields
+            .iter()
+            .map(|(field, status)| format!("{field}: {status}"))
+            .collect();
+        print_output(&lines.join("\n"));
+    }
+
+    fields.iter().all(|&(_, status)| status == "same")
+}
+
+#[cfg(feature = "sign")]
+fn load_signing_key(path: &Path) -> ed25519_dalek::SigningKey {
+    use ed25519_dalek::pkcs8::DecodePrivateKey;
+    let pem = retry_io(|| fs::read_to_string(path))
+        .unwrap_or_else(|e| fail(EXIT_IO, format!("failed to read {}: {}", path.display(), e)));
+    ed25519_dalek::SigningKey::from_pkcs8_pem(&pem).unwrap_or_else(|e| {
+        fail(
+            EXIT_USAGE,
+            format!("invalid Ed25519 private key {}: {}", path.display(), e),
+        )
+    })
+}
+
+#[cfg(feature = "sign")]
+fn load_verifying_key(path: &Path) -> ed25519_dalek::VerifyingKey {
+    use ed25519_dalek::pkcs8::DecodePublicKey;
+    let pem = retry_io(|| fs::read_to_string(path))
+        .unwrap_or_else(|e| fail(EXIT_IO, format!("failed to read {}: {}", path.display(), e)));
+    ed25519_dalek::VerifyingKey::from_public_key_pem(&pem).unwrap_or_else(|e| {
+        fail(
+            EXIT_USAGE,
+            format!("invalid Ed25519 public key {}: {}", path.display(), e),
+        )
+    })
+}
+
+#[cfg(feature = "sign")]
+fn sign_bundle(bundle: Bundle, key: Option<&ed25519_dalek::SigningKey>) -> Bundle {
+    use ed25519_dalek::Signer;
+    let Some(key) = key else {
+        return bundle;
+    };
+    let signature = key.sign(&bundle.signed_data());
+    Bundle {
+        signature: Some(signature.to_bytes().to_vec()),
+        ..bundle
+    }
+}
+
+// Runs before the password is checked, so a forged bundle is reported as such rather than as a
+// mismatch
+#[cfg(feature = "sign")]
+fn check_bundle_signature(bundle: &Bundle, key: &ed25519_dalek::VerifyingKey) {
+    let Some(signature) = &bundle.signature else {
+        fail(EXIT_BAD_SIGNATURE, "The bundle is not signed");
+    };
+    let valid = ed25519_dalek::Signature::from_slice(signature)
+        .is_ok_and(|signature| key.verify_strict(&bundle.signed_data(), &signature).is_ok());
+    if !valid {
+        fail(EXIT_BAD_SIGNATURE, "The bundle's signature is invalid");
+    }
+}
+
+fn read_bundle(path: &Path, min_version: Option<u8>) -> Bundle {
+    let data = retry_io(|| fs::read(path))
+        .unwrap_or_else(|e| fail(EXIT_IO, format!("failed to read {}: {}", path.display(), e)));
+    if let (Some(min), Ok(version)) = (min_version, bundle::version(&data)) {
+        validate_format_version(version, min).unwrap_or_else(|e| fail(EXIT_DOWNGRADE, e));
+    }
+    let bundle = Bundle::decode(&data).unwrap_or_else(|e| {
+        fail(
+            EXIT_USAGE,
+            format!("invalid bundle {}: {}", path.display(), e),
+        )
+    });
+    if bundle.mk_params.yescrypt().is_err() || bundle.dpk_params.yescrypt().is_err() {
+        fail(
+            EXIT_USAGE,
+            format!("invalid bundle {}: bad yescrypt params", path.display()),
+        );
+    }
+
+    bundle
+}
+
+fn report_metadata(bundle: &Bundle) {
+    if !verbose() {
+        return;
+    }
+    let Some(metadata) = &bundle.metadata else {
+        info("bundle: no metadata");
+        return;
+    };
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let days = now.saturating_sub(metadata.created) / 86400;
+    info(format!(
+        "bundle: made by MKDF {} (bundle format {}) at Unix time {}, {} days ago",
+        metadata.tool_version, metadata.format_version, metadata.created, days
+    ));
+}
+
+// yescrypt outputs are encoded with its own flavor of base64 (the one of SHA-crypt); bundles hold
+// them as raw bytes
+fn decode_hash(hash: &str) -> Vec<u8> {
+    Base64ShaCrypt::decode_vec(hash).expect("yescrypt outputs are valid base64")
+}
+
+fn encode_hash(hash: &[u8]) -> String {
+    Base64ShaCrypt::encode_string(hash)
+}
+
+fn readpw(mut input: impl Read, delimiter: Option<u8>) -> Result<Vec<u8>, io::Error> {
+    let mut buf = Vec::new();
+    if let Some(delimiter) = delimiter {
+        // The delimiter frames the password exactly, so nothing else is trimmed
+        io::BufReader::new(input).read_until(delimiter, &mut buf)?;
+        if buf.last() == Some(&delimiter) {
+            buf.pop();
+        }
+        return Ok(buf);
+    }
+    input.read_to_end(&mut buf)?;
+
+    // Remove trailing newline(s)
+    while matches!(buf.last(), Some(b'\n' | b'\r')) {
+        buf.pop();
+    }
+
+    Ok(buf)
+}
+
+const IO_ATTEMPTS: u32 = 5;
+const IO_BACKOFF: Duration = Duration::from_millis(10);
+
+// Busy or networked filesystems occasionally fail an operation that succeeds when retried; other
+// errors (e.g. a missing file or a denied access) are returned right away
+fn retry_io<T>(mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut backoff = IO_BACKOFF;
+    for _ in 1..IO_ATTEMPTS {
+        match op() {
+            Err(e) if is_transient(&e) => {
+                if verbose() {
+                    info(format!("Transient IO error ({e}), retrying in {backoff:?}"));
+                }
+                std::thread::sleep(backoff);
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+
+    op()
+}
+
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::ResourceBusy
+    )
+}
+
+// Goes through /dev/fd rather than taking ownership of the raw descriptor, which would need unsafe
+fn open_fd(fd: u32) -> fs::File {
+    retry_io(|| fs::File::open(format!("/dev/fd/{fd}")))
+        .unwrap_or_else(|e| fail(EXIT_IO, format!("failed to open file descriptor {fd}: {e}")))
+}
+
+fn prompt_password(confirm: bool) -> Vec<u8> {
+    let read = |prompt: &str| {
+        rpassword::prompt_password(prompt)
+            .unwrap_or_else(|e| fail(EXIT_IO, format!("failed to read password: {}", e)))
+    };
+    let password = read("Password: ");
+    if confirm && read("Confirm password: ") != password {
+        fail(EXIT_MISMATCH, "The passwords do not match");
+    }
+
+    password.into_bytes()
+}
+
+fn build_params(
+    stage: &str,
+    mode: Mode,
+    n: u64,
+    r: u32,
+    p: u32,
+    t: u32,
+    force: bool,
+) -> StageParams {
+    let params = StageParams { mode, n, r, p, t };
+    if let Err(e) = check_params(stage, &params, force) {
+        fail(EXIT_USAGE, e);
+    }
+
+    params
+}
+
+fn check_params(stage: &str, params: &StageParams, force: bool) -> Result<(), String> {
+    let StageParams { n, r, p, .. } = *params;
+    if n < 2 || !n.is_power_of_two() {
+        return Err(format!("The {stage} N must be a power of 2 greater than 1"));
+    }
+    if r == 0 || p == 0 {
+        return Err(format!("The {stage} r and p must be at least 1"));
+    }
+    if !force && (n < MIN_N || r < MIN_R || p < MIN_P) {
+        return Err(format!(
+            "The {stage} params (N={n}, r={r}, p={p}) are below the security floor \
+             (N >= {MIN_N}, r >= {MIN_R}, p >= {MIN_P}); pass --force to use them anyway"
+        ));
+    }
+    params
+        .yescrypt()
+        .map(|_| ())
+        .map_err(|e| format!("Invalid {stage} params: {e}"))
+}
+
+// Bundles record their own params, which are held to the same floor as the ones of the flags
+fn check_bundle_params(bundle: &Bundle, force: bool) -> Result<(), String> {
+    check_params("bundle MK", &bundle.mk_params, force)?;
+    check_params("bundle DPK", &bundle.dpk_params, force)
+}
+
+// Each file is prefixed with its length as a big-endian u64 so that no two different sets of files
+// concatenate to the same password (e.g. "ab" + "c" vs "a" + "bc")
+fn read_input_files(paths: &[PathBuf]) -> Vec<u8> {
+    let mut password = Vec::new();
+    for path in paths {
+        let contents = retry_io(|| fs::read(path))
+            .unwrap_or_else(|e| fail(EXIT_IO, format!("failed to read {}: {}", path.display(), e)));
+        password.extend_from_slice(&(contents.len() as u64).to_be_bytes());
+        password.extend_from_slice(&contents);
+    }
+
+    password
+}
+
+fn memory_cost(params: &StageParams) -> u64 {
+    128 * params.n * u64::from(params.r) * u64::from(params.p)
+}
+
+// The estimate times one yescrypt run at the floor params and scales it by the work of each stage,
+// which grows linearly with N * r * p * (t + 1)
+fn print_cost_report(stages: &[Stage], params: &DerivationParams, json: bool) {
+    let work = |params: &StageParams| {
+        params.n as f64 * f64::from(params.r) * f64::from(params.p) * f64::from(params.t + 1)
+    };
+    let floor = StageParams {
+        mode: params.mk.mode,
+        n: MIN_N,
+        r: MIN_R,
+        p: MIN_P,
+        t: 0,
+    };
+    let start = Instant::now();
+    expect_kdf(generate_hash_mk(b"", &[0; SALT_LEN], &floor));
+    let ms_per_work = start.elapsed().as_secs_f64() * 1000.0 / work(&floor);
+
+    // The MK always runs first, then the digest and the DPK in parallel
+    let mut runs = vec![&params.mk];
+    // A fast digest costs next to nothing next to the yescrypt runs
+    if stages.contains(&Stage::Digest) && params.digest == DigestAlgo::Yescrypt {
+        runs.push(&params.mk);
+    }
+    if stages.contains(&Stage::Dpk) {
+        runs.push(&params.dpk);
+    }
+    let memory: u64 = runs.iter().map(|p| memory_cost(p)).sum();
+    let peak = memory_cost(&params.mk).max(runs[1..].iter().map(|p| memory_cost(p)).sum());
+    let time = runs.iter().map(|p| work(p)).sum::<f64>() * ms_per_work;
+
+    if json {
+        let report = serde_json::json!({
+            "runs": runs.len(),
+            "memory": memory,
+            "peak_memory": peak,
+            "time_ms": time.round(),
+        });
+        print_json(report);
+    } else {
+        print_output(&format!(
+            "cost: {} yescrypt run(s), {} MiB in total (peak {} MiB), about {:.0} ms of CPU time",
+            runs.len(),
+            memory >> 20,
+            peak >> 20,
+            time
+        ));
+    }
+}
+
+// Keeps the MK params and the DPK's r and p as given, and picks the largest DPK N that fits both
+// budgets, extrapolating from a quick measurement since the time grows linearly with N
+fn tune(params: &DerivationParams) {
+    if !io::stdin().is_terminal() || !io::stderr().is_terminal() {
+        fail(EXIT_USAGE, "mkdf tune is interactive and needs a terminal");
+    }
+    let ask = |question: &str| -> u64 {
+        loop {
+            eprint!("{question} ");
+            let mut answer = String::new();
+            if io::stdin().read_line(&mut answer).unwrap_or(0) == 0 {
+                fail(EXIT_IO, "no answer given");
+            }
+            match answer.trim().parse() {
+                Ok(value) if value > 0 => break value,
+                _ => eprintln!("Please enter a positive whole number."),
+            }
+        }
+    };
+    let target_ms = ask("Target unlock time, in milliseconds (e.g. 1000)?");
+    let ram_mib = ask("Memory available to each run, in MiB (e.g. 256)?");
+
+    eprintln!("Measuring...");
+    let measure = |stage: &StageParams| {
+        let start = Instant::now();
+        expect_kdf(generate_hash_mk(b"", &[0; SALT_LEN], stage));
+        start.elapsed().as_secs_f64() * 1000.0
+    };
+    let mk_ms = measure(&params.mk);
+    let probe = StageParams {
+        n: MIN_N,
+        ..params.dpk
+    };
+    let ms_per_n = measure(&probe) / MIN_N as f64;
+    // The digest and the DPK run in parallel unless there is a single thread
+    let total = |dpk_ms: f64| {
+        if SERIAL.load(Ordering::Relaxed) {
+            2.0 * mk_ms + dpk_ms
+        } else {
+            mk_ms + mk_ms.max(dpk_ms)
+        }
+    };
+
+    let mut dpk = probe;
+    loop {
+        let next = StageParams {
+            n: dpk.n * 2,
+            ..dpk
+        };
+        let fits_ram = memory_cost(&next) <= ram_mib << 20;
+        if !fits_ram || total(ms_per_n * next.n as f64) > target_ms as f64 {
+            break;
+        }
+        dpk = next;
+    }
+    let measured = total(measure(&dpk));
+
+    println!(
+        "Recommended DPK params (about {:.0} ms per run, {} MiB of memory for the DPK):",
+        measured,
+        memory_cost(&dpk) >> 20
+    );
+    println!();
+    println!("    --dpk-n {} --dpk-r {} --dpk-p {}", dpk.n, dpk.r, dpk.p);
+    println!();
+    println!("Pass the same params on every hash and verification, e.g.:");
+    println!();
+    // Measured with the given t, which has to be passed again
+    let t = match dpk.t {
+        0 => String::new(),
+        t => format!(" --t {t}"),
+    };
+    println!(
+        "    mkdf --hash --mk-n {} --mk-r {} --mk-p {} --dpk-n {} --dpk-r {} --dpk-p {}{}",
+        params.mk.n, params.mk.r, params.mk.p, dpk.n, dpk.r, dpk.p, t
+    );
+    if measured > target_ms as f64 || memory_cost(&dpk) > ram_mib << 20 {
+        warn("even the smallest DPK params exceed the budget; consider lowering the MK params");
+    }
+    if memory_cost(&dpk) < SOFT_MIN_DPK_MEMORY {
+        warn(format!(
+            "these params use less than the recommended {} MiB of memory",
+            SOFT_MIN_DPK_MEMORY >> 20
+        ));
+    }
+}
+
+fn warn_weak_params(dpk: &StageParams) {
+    let memory = memory_cost(dpk);
+    if memory < SOFT_MIN_DPK_MEMORY {
+        warn(format!(
+            "the DPK params only use {} MiB of memory (at least {} MiB recommended); \
+             these params offer limited protection against GPU attackers",
+            memory >> 20,
+            SOFT_MIN_DPK_MEMORY >> 20
+        ));
+    }
+}
+
+fn generate_hash_mk(password: &[u8], salt: &[u8], params: &StageParams) -> Result<String, String> {
+    hash_field(&run_yescrypt(password, salt, params)?)
+}
+
+fn generate_phc(password: &[u8], salt: &[u8], params: &StageParams) -> Result<String, String> {
+    Ok(run_yescrypt(password, salt, params)?.as_str().to_string())
+}
+
+// Lets the tests swap yescrypt for a fast mock to exercise the pipeline around it. It is only
+// compiled into test builds, and only applies to the thread that set it
+#[cfg(test)]
+type Kdf = fn(&[u8], &[u8], &StageParams) -> Result<yescrypt::PasswordHash, String>;
+#[cfg(test)]
+thread_local! {
+    static MOCK_KDF: std::cell::Cell<Option<Kdf>> = const { std::cell::Cell::new(None) };
+}
+
+// The params were checked when they were built, so an error here is a bug in MKDF or in yescrypt.
+// It is returned rather than reported, since the server has to keep running
+fn run_yescrypt(
+    input: &[u8],
+    salt: &[u8],
+    params: &StageParams,
+) -> Result<yescrypt::PasswordHash, String> {
+    #[cfg(test)]
+    if let Some(kdf) = MOCK_KDF.get() {
+        return kdf(input, salt, params);
+    }
+    let params = params
+        .yescrypt()
+        .map_err(|e| format!("invalid yescrypt params: {e}"))?;
+    let hash = move |input: &[u8], salt: &[u8]| {
+        Yescrypt
+            .hash_password_with_params(input, salt, params)
+            .map_err(|e| format!("yescrypt failed: {e}"))
+    };
+    let Some(&(deadline, timeout)) = DEADLINE.get() else {
+        return hash(input, salt);
+    };
+    // yescrypt can't be interrupted midway, so under --timeout-ms it runs on a thread of its own
+    // that is left behind once the budget is spent. The output is only written after the last run
+    // has returned, so a run that times out never leaves it half written
+    let (input, salt) = (Zeroizing::new(input.to_vec()), salt.to_vec());
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        // The receiver is gone if the run timed out, and then there is no one to tell
+        let _ = sender.send(hash(&input, &salt));
+    });
+    match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => fail(
+            EXIT_TIMEOUT,
+            format!(
+                "The derivation took longer than {} ms; aborting",
+                timeout.as_millis()
+            ),
+        ),
+        Err(RecvTimeoutError::Disconnected) => Err("yescrypt failed".to_string()),
+    }
+}
+
+// The hash is the last field of the PHC string yescrypt outputs
+fn hash_field(hash: &yescrypt::PasswordHash) -> Result<String, String> {
+    match hash.fields().last() {
+        Some(field) => Ok(field.as_str().to_string()),
+        None => Err("unexpected empty hash output".to_string()),
+    }
+}
+
+fn derive_dpk(
+    password: &[u8],
+    mk: &str,
+    salt: &[u8],
+    params: &DerivationParams,
+) -> Result<Zeroizing<String>, String> {
+    let input = match params.dpk_source {
+        DpkSource::Password => password,
+        DpkSource::Mk => mk.as_bytes(),
+    };
+    hash_field(&run_yescrypt(input, salt, &params.dpk)?).map(Zeroizing::new)
+}
+
+// For the callers that report a failure of the KDF helpers above as an internal error and exit
+fn expect_kdf<T>(result: Result<T, String>) -> T {
+    result.unwrap_or_else(|e| fail(EXIT_SOFTWARE, e))
+}
+
+// Carries the exit code of a failure up to main
+struct Exit(i32);
+
+// Reports a fatal error and exits; under --json the message goes out as
+// {"error": "...", "code": N} so callers can parse failures like they parse results. It unwinds up
+// to main rather than exiting right away so that the secrets on the way are wiped. Only the first
+// failure is reported, in case both sides of a join fail
+fn fail(code: i32, message: impl std::fmt::Display) -> ! {
+    #[cfg(feature = "tui")]
+    tui::restore();
+    if FAILED.swap(true, Ordering::Relaxed) {
+        std::panic::resume_unwind(Box::new(Exit(code)));
+    }
+    if JSON.load(Ordering::Relaxed) {
+        let error = serde_json::json!({
+            "error": message.to_string(),
+            "code": code,
+            "version": JSON_FORMAT_VERSION,
+        });
+        eprintln!("{}", error);
+    } else {
+        eprintln!("{}", message);
+    }
+    std::panic::resume_unwind(Box::new(Exit(code)));
+}
+
+fn warn(message: impl std::fmt::Display) {
+    if JSON.load(Ordering::Relaxed) {
+        let warning = serde_json::json!({
+            "warning": message.to_string(),
+            "version": JSON_FORMAT_VERSION,
+        });
+        eprintln!("{}", warning);
+    } else {
+        eprintln!("Warning: {}", message);
+    }
+}
+
+// Reports a diagnostic of --verbose or --time; under --json it goes out as {"info": "..."}, so that
+// STDERR only holds JSON lines
+fn info(message: impl std::fmt::Display) {
+    if JSON.load(Ordering::Relaxed) {
+        let info = serde_json::json!({
+            "info": message.to_string(),
+            "version": JSON_FORMAT_VERSION,
+        });
+        eprintln!("{}", info);
+    } else {
+        eprintln!("{}", message);
+    }
+}
+
+fn verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
+// Salt of the yescrypt run that deterministic salts are derived from
+const DETERMINISTIC_SALT_LABEL: &[u8; SALT_LEN] = b"mkdf-determ-salt";
+
+// The password goes through yescrypt with the MK params first, so that the salts cost as much to
+// test a password guess against as the MK does. Each salt is then SHA-256 of a per-salt label and
+// that output, truncated to the salt length; the label keeps the three salts apart
+fn derive_salts(password: &[u8], mk: &StageParams) -> Result<[[u8; SALT_LEN]; 3], String> {
+    let seed = Zeroizing::new(generate_hash_mk(password, DETERMINISTIC_SALT_LABEL, mk)?);
+    Ok(std::array::from_fn(|i| {
+        let mut hasher = Sha256::new();
+        hasher.update(format!("MKDF deterministic salt {}\0", i + 1).as_bytes());
+        hasher.update(seed.as_bytes());
+        let mut salt = [0u8; SALT_LEN];
+        salt.copy_from_slice(&hasher.finalize()[..SALT_LEN]);
+        salt
+    }))
+}
+
+fn generate_salt() -> [u8; SALT_LEN] {
+    let mut salt = [0u8; SALT_LEN];
+    match OsRng.try_fill_bytes(&mut salt) {
+        Ok(salt) => salt,
+        Err(e) => {
+            fail(EXIT_IO, format!("Error: {}", e));
+        }
+    };
+    if verbose() {
+        info(format!(
+            "salt: drew {SALT_LEN} bytes from {}",
+            entropy_source()
+        ));
+    }
+    salt
+}
+
+// OsRng goes through the getrandom crate, which uses the getrandom(2) syscall where the kernel has
+// it and only falls back to /dev/urandom once it is seeded
+fn entropy_source() -> &'static str {
+    if cfg!(any(target_os = "linux", target_os = "android")) {
+        "the OS CSPRNG (getrandom syscall, /dev/urandom fallback)"
+    } else {
+        "the OS CSPRNG"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PHC: &str = "$y$j9T$F5Jx5fExrKuPp53xLKQ..0$Ilc1TT6PFm2TahvVep0wbEbM09cpoGMYBz7Ep2ycnF7";
+
+    fn stage(n: u64, r: u32, p: u32) -> StageParams {
+        StageParams {
+            mode: Mode::Rw,
+            n,
+            r,
+            p,
+            t: 0,
+        }
+    }
+
+    fn bundle(mk_params: StageParams) -> Bundle {
+        Bundle {
+            mk_params,
+            dpk_params: stage(32768, 32, 1),
+            salt1: vec![1; SALT_LEN],
+            mk: None,
+            phash: Some(vec![7; HASH_LEN]),
+            phash2: None,
+            salt2: Some(vec![2; SALT_LEN]),
+            dpk: None,
+            salt3: Some(vec![3; SALT_LEN]),
+            dpk_source: DpkSource::Mk,
+            digest: DigestAlgo::Yescrypt,
+            metadata: None,
+            signature: None,
+        }
+    }
+
+    #[test]
+    fn phash_accepts_bare_and_phc() {
+        let bare = encode_hash(&[7; HASH_LEN]);
+        assert_eq!(validate_phash(&bare, DigestAlgo::Yescrypt), Ok(()));
+        assert_eq!(validate_phash(PHC, DigestAlgo::Yescrypt), Ok(()));
+        let sha512 = encode_hash(&[7; 64]);
+        assert_eq!(validate_phash(&sha512, DigestAlgo::Sha512), Ok(()));
+        assert!(validate_phash(&bare, DigestAlgo::Sha512).is_err());
+    }
+
+    #[test]
+    fn phash_rejects_non_phc() {
+        for phash in [
+            "",
+            "not a phash",
+            "$2b$10$N9qo8uLOickgx2ZMRZoMyeIjZAgcfl7p92ldGxad68LJZdL17lhWy",
+            &encode_hash(&[7; HASH_LEN - 1]),
+        ] {
+            assert!(
+                validate_phash(phash, DigestAlgo::Yescrypt).is_err(),
+                "{phash}"
+            );
+        }
+    }
+
+    #[test]
+    fn phash_rejects_malformed_phc() {
+        for phash in [
+            "$y$",
+            "$y$j9T$F5Jx5fExrKuPp53xLKQ..0",
+            "$y$???$F5Jx5fExrKuPp53xLKQ..0$Ilc1TT6PFm2TahvVep0wbEbM09cpoGMYBz7Ep2ycnF7",
+            "$y$j9T$F5Jx5fExrKuPp53xLKQ..0$Ilc1TT6PFm2TahvVep0wbEbM09cpoGMYBz7Ep2y",
+            "$y$j9T$F5Jx5fExrKuPp53xLKQ..0$Ilc1TT6PFm2TahvVep0wbEbM09cpoGMYBz7Ep2ycnF7$x",
+        ] {
+            assert!(
+                validate_phash(phash, DigestAlgo::Yescrypt).is_err(),
+                "{phash}"
+            );
+        }
+        assert!(validate_phash(PHC, DigestAlgo::Sha256).is_err());
+    }
+
+    #[test]
+    fn hex_rejects_bad_lengths() {
+        let salt = "d1a58e17f9ea11c9fe1e26654d89e6b6";
+        for hex in [&salt[..1], &salt[..3], &salt[..31]] {
+            assert!(decode_hex::<SALT_LEN>(hex).is_err(), "{hex}");
+        }
+        // Even lengths that are too short or too long
+        for hex in ["", &salt[..2], &salt[..30], &format!("{salt}00")] {
+            assert!(decode_hex::<SALT_LEN>(hex).is_err(), "{hex}");
+        }
+        assert!(decode_hex_vec("abc").is_err());
+    }
+
+    #[test]
+    fn hex_rejects_non_ascii_and_non_hex() {
+        assert!(decode_hex_vec("é0").is_err());
+        assert!(decode_hex::<SALT_LEN>("d1a58e17f9ea11c9fe1e26654d89e6é").is_err());
+        assert!(decode_hex_vec("0g").is_err());
+        assert!(decode_hex_vec("0 ").is_err());
+    }
+
+    #[test]
+    fn hex_accepts_either_case() {
+        assert_eq!(decode_hex_vec("aBcD09"), Ok(vec![0xab, 0xcd, 0x09]));
+        assert_eq!(
+            decode_hex::<SALT_LEN>("D1A58E17F9EA11C9FE1E26654D89E6B6"),
+            decode_hex::<SALT_LEN>("d1a58e17f9ea11c9fe1e26654d89e6b6")
+        );
+    }
+
+    #[test]
+    fn params_below_the_floor_need_force() {
+        for (n, r, p) in [
+            (MIN_N / 2, MIN_R, MIN_P),
+            (MIN_N, MIN_R / 2, MIN_P),
+            (2, 1, 1),
+        ] {
+            let params = stage(n, r, p);
+            let e = check_params("MK", &params, false).unwrap_err();
+            assert!(e.contains("security floor"), "{e}");
+            assert_eq!(check_params("MK", &params, true), Ok(()));
+        }
+        assert_eq!(
+            check_params("MK", &stage(MIN_N, MIN_R, MIN_P), false),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn force_doesnt_permit_invalid_params() {
+        for (n, r, p) in [
+            (0, 8, 1),
+            (1, 8, 1),
+            (3000, 8, 1),
+            (1024, 0, 1),
+            (1024, 8, 0),
+        ] {
+            assert!(check_params("MK", &stage(n, r, p), true).is_err());
+        }
+    }
+
+    #[test]
+    fn bundles_are_held_to_the_floor() {
+        let weak = bundle(stage(2, 1, 1)).encode();
+        let e = check_bundle(b"passwd", &weak, None, None, 1024, false).unwrap_err();
+        assert!(e.contains("security floor"), "{e}");
+        assert_eq!(
+            check_bundle(b"passwd", &weak, None, None, 1024, true),
+            Ok(false)
+        );
+        assert!(check_bundle_params(&bundle(stage(2048, 8, 1)), false).is_ok());
+    }
+
+    #[test]
+    fn all_zero_salts_need_force() {
+        let (zero, random) = ([0; SALT_LEN], generate_salt());
+        for salts in [[&zero[..], &random], [&random, &zero]] {
+            let e = validate_salt_entropy(&salts, false).unwrap_err();
+            assert!(e.contains("all zeros"), "{e}");
+            assert_eq!(validate_salt_entropy(&salts, true), Ok(()));
+        }
+        assert_eq!(validate_salt_entropy(&[&random, &random], false), Ok(()));
+    }
+
+    #[test]
+    fn empty_hash_output_is_an_error() {
+        let empty = yescrypt::PasswordHash::from_id("y").unwrap();
+        assert_eq!(
+            hash_field(&empty),
+            Err("unexpected empty hash output".to_string())
+        );
+    }
+
+    #[test]
+    fn yescrypt_errors_are_returned() {
+        let params = stage(3, 8, 1);
+        assert!(generate_hash_mk(b"passwd", &[1; SALT_LEN], &params).is_err());
+        assert!(generate_phc(b"passwd", &[1; SALT_LEN], &params).is_err());
+    }
+
+    // Stands in for yescrypt: as deterministic and as sensitive to every input, but instant
+    fn mock_kdf(
+        input: &[u8],
+        salt: &[u8],
+        params: &StageParams,
+    ) -> Result<yescrypt::PasswordHash, String> {
+        let mut hasher = Sha256::new();
+        hasher.update(u32::from(params.mode).to_be_bytes());
+        hasher.update(params.n.to_be_bytes());
+        hasher.update(
+            [params.r, params.p, params.t]
+                .map(u32::to_be_bytes)
+                .concat(),
+        );
+        hasher.update(salt);
+        hasher.update(input);
+        let hash = encode_hash(&hasher.finalize());
+        yescrypt::PasswordHash::new(format!("$y$mock${hash}")).map_err(|e| e.to_string())
+    }
+
+    fn default_params(dpk_source: DpkSource) -> DerivationParams {
+        DerivationParams {
+            mk: stage(2048, 8, 1),
+            dpk: stage(32768, 32, 1),
+            dpk_source,
+            digest: DigestAlgo::Yescrypt,
+        }
+    }
+
+    #[test]
+    fn pipeline_with_a_mock_kdf() {
+        MOCK_KDF.set(Some(mock_kdf));
+        let params = default_params(DpkSource::Mk);
+        let [salt1, salt2, salt3] = [[1; SALT_LEN], [2; SALT_LEN], [3; SALT_LEN]];
+        let mk = expect_kdf(generate_hash_mk(b"passwd", &salt1, &params.mk));
+        let phash = expect_kdf(digest_mk(&mk, &salt2, &params, false));
+
+        let dpk = verify_password(b"passwd", &salt1, &salt2, &salt3, &phash, None, &params);
+        assert_eq!(dpk, Some(expect_kdf(derive_dpk(b"", &mk, &salt3, &params))));
+        assert_eq!(
+            verify_password(b"passwd!", &salt1, &salt2, &salt3, &phash, None, &params),
+            None
+        );
+        // Swapping the salts of the stages must break the match
+        assert_eq!(
+            verify_password(b"passwd", &salt2, &salt1, &salt3, &phash, None, &params),
+            None
+        );
+        // The DPK follows --dpk-source, while the phash doesn't depend on it
+        let from_password = default_params(DpkSource::Password);
+        let other = verify_password(
+            b"passwd",
+            &salt1,
+            &salt2,
+            &salt3,
+            &phash,
+            None,
+            &from_password,
+        );
+        assert!(other.is_some() && other != dpk);
+    }
+
+    #[test]
+    fn empty_mock_output_is_an_error() {
+        MOCK_KDF.set(Some(|_, _, _| {
+            Ok(yescrypt::PasswordHash::from_id("y").unwrap())
+        }));
+        let params = default_params(DpkSource::Mk);
+        let error = Err("unexpected empty hash output".to_string());
+        assert_eq!(
+            generate_hash_mk(b"passwd", &[1; SALT_LEN], &params.mk),
+            error
+        );
+        assert_eq!(
+            derive_dpk(b"passwd", "", &[3; SALT_LEN], &params).map(|dpk| dpk.to_string()),
+            error
+        );
+        assert_eq!(
+            check_mk("mk", &[2; SALT_LEN], "x", None, &params),
+            error.map(|_| false)
+        );
+    }
+
+    #[test]
+    fn phc_phash_carries_its_mk_params() {
+        let defaults = default_params(DpkSource::Mk);
+        let hashed = DerivationParams {
+            mk: stage(4096, 8, 1),
+            ..defaults
+        };
+        let output = hash_password(b"passwd", &[Stage::Digest], &hashed, None, true, false);
+        let (phash, salt2) = (output.phash.unwrap(), output.salt2.unwrap());
+        assert!(phash.starts_with("$y$"), "{phash}");
+
+        // Verified as if --mk-n weren't given
+        let params = DerivationParams {
+            mk: phash_mk_params(&phash, &defaults.mk, false),
+            ..defaults
+        };
+        assert_eq!(params.mk, hashed.mk);
+        let check = |params| check_password(b"passwd", &output.salt1, &salt2, &phash, None, params);
+        assert!(check(&params).is_some());
+        assert!(check(&defaults).is_none());
+    }
+
+    #[test]
+    fn time_cost_round_trips() {
+        let untimed = default_params(DpkSource::Mk);
+        let timed = DerivationParams {
+            mk: StageParams { t: 1, ..untimed.mk },
+            ..untimed
+        };
+        for phc in [false, true] {
+            let output = hash_password(b"passwd", &[Stage::Digest], &timed, None, phc, false);
+            let (phash, salt2) = (output.phash.unwrap(), output.salt2.unwrap());
+            let check =
+                |params| check_password(b"passwd", &output.salt1, &salt2, &phash, None, params);
+            assert!(check(&timed).is_some());
+            assert!(check(&untimed).is_none());
+            if phc {
+                assert_eq!(phash_mk_params(&phash, &timed.mk, false), timed.mk);
+            }
+        }
+    }
+
+    #[test]
+    fn salts_may_have_surrounding_whitespace() {
+        let hex = "000102030405060708090a0b0c0d0e0f";
+        let base64 = "AAECAwQFBgcICQoLDA0ODw";
+        let expected: [u8; SALT_LEN] = std::array::from_fn(|i| i as u8);
+        for given in [format!(" {hex}"), format!("{hex} "), format!("\t{hex}\r\n")] {
+            let salt = parse_salt_arg(&given).unwrap();
+            assert_eq!(decode_salt(&salt, SaltEncoding::Hex), Ok(expected));
+        }
+        let salt = parse_salt_arg(&format!(" {base64}\n")).unwrap();
+        assert_eq!(decode_salt(&salt, SaltEncoding::Base64url), Ok(expected));
+    }
+
+    #[test]
+    fn salts_may_only_hold_group_separators() {
+        let grouped = group_salt("000102030405060708090a0b0c0d0e0f", Some(4));
+        assert_eq!(
+            parse_salt_arg(&format!("{grouped}\n")).unwrap(),
+            "000102030405060708090a0b0c0d0e0f"
+        );
+        for given in [
+            "00010203040506070809\t0a0b0c0d0e0f",
+            "00010203040506070809\n0a0b0c0d0e0f",
+            "00010203040506070809  0a0b0c0d0e0f",
+            "AAECAwQFBgcI\tCQoLDA0ODw",
+        ] {
+            assert!(parse_salt_arg(given).is_err(), "{given:?}");
+        }
+    }
+
+    #[test]
+    fn bundles_are_held_to_the_memory_bound() {
+        // 128 * N * r * p bytes: 2 TiB, which mustn't reach yescrypt
+        let huge = bundle(stage(1 << 31, 8, 1)).encode();
+        for force in [false, true] {
+            let e = check_bundle(b"passwd", &huge, None, None, 1024, force).unwrap_err();
+            assert!(e.contains("--max-bundle-memory"), "{e}");
+        }
+        // The DPK stage isn't run, but is bounded all the same
+        let e = check_bundle(
+            b"passwd",
+            &bundle(stage(2048, 8, 1)).encode(),
+            None,
+            None,
+            16,
+            false,
+        )
+        .unwrap_err();
+        assert!(e.contains("bundle DPK"), "{e}");
+    }
+
+    #[test]
+    fn bundles_are_held_to_min_params() {
+        let data = bundle(stage(2048, 8, 1)).encode();
+        let e = check_bundle(b"passwd", &data, Some((4096, 8, 1)), None, 1024, false).unwrap_err();
+        assert!(e.contains("below the required minimum"), "{e}");
+        assert!(validate_min_params("phash", (2048, 8, 1), (2048, 8, 1)).is_ok());
+        assert!(validate_min_params("phash", (2048, 8, 1), (1024, 16, 1)).is_err());
+    }
+
+    #[test]
+    fn bundles_are_held_to_min_format_version() {
+        let data = bundle(stage(2048, 8, 1)).encode();
+        assert_eq!(bundle::version(&data), Ok(bundle::VERSION));
+        let min = Some(bundle::VERSION + 1);
+        let e = check_bundle(b"passwd", &data, None, min, 1024, false).unwrap_err();
+        assert!(e.contains("below --min-format-version"), "{e}");
+        // A bundle in an older format is refused as a downgrade rather than as unreadable
+        let mut old = data.clone();
+        old[4] = 0;
+        let e = check_bundle(b"passwd", &old, None, Some(1), 1024, false).unwrap_err();
+        assert!(e.contains("below --min-format-version"), "{e}");
+        assert!(bundle::version(b"MKDX\x01").is_err());
+    }
+
+    #[test]
+    fn deterministic_salts_go_through_the_kdf() {
+        MOCK_KDF.set(Some(mock_kdf));
+        let mk = stage(2048, 8, 1);
+        let salts = derive_salts(b"passwd", &mk).unwrap();
+        assert_eq!(derive_salts(b"passwd", &mk), Ok(salts));
+        assert_ne!(derive_salts(b"passwd2", &mk), Ok(salts));
+        assert_ne!(derive_salts(b"passwd", &stage(4096, 8, 1)), Ok(salts));
+        assert!(salts[0] != salts[1] && salts[1] != salts[2] && salts[0] != salts[2]);
+        MOCK_KDF.set(None);
+    }
 }
@@ -0,0 +1,180 @@
+/*
+* mkdf — password-based master key derivation and verification tool
+* Copyright (C) 2026 L. M. Oukaci
+*
+* Contact: ouka.lotfi@gmail.com
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Verification daemon.
+//!
+//! Clients connect to a Unix domain socket and send a single request per connection: the password,
+//! then a binary bundle, each prefixed with its length as a big-endian u32. The server answers with
+//! a single line, `Match`, `Mismatch`, `RateLimited` or `Error: <reason>`, then closes the
+//! connection.
+//!
+//! Requests are served one at a time. Each client, identified by the user ID it connects with, may
+//! make a given number of requests per minute; the requests past that are refused without reading
+//! them. The request buffers are wiped as soon as the request has been served, and bundles whose
+//! params take more memory than allowed are refused before yescrypt runs.
+
+use crate::{check_bundle, fail, info, verbose, EXIT_IO};
+use rustix::net::sockopt::socket_peercred;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+// Passwords and bundles are both far shorter than this
+const MAX_FIELD_LEN: u32 = 1 << 16;
+// Keeps a client that stops sending from holding up everyone else
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+pub fn serve(
+    socket: &Path,
+    rate_limit: u32,
+    min_version: Option<u8>,
+    max_memory: u64,
+    force: bool,
+) -> ! {
+    remove_stale_socket(socket);
+    let listener = UnixListener::bind(socket).unwrap_or_else(|e| {
+        fail(
+            EXIT_IO,
+            format!("failed to listen on {}: {}", socket.display(), e),
+        )
+    });
+    let mut requests: HashMap<u32, VecDeque<Instant>> = HashMap::new();
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => fail(EXIT_IO, format!("failed to accept a connection: {e}")),
+        };
+        let reply = handle(
+            &mut stream,
+            &mut requests,
+            rate_limit,
+            min_version,
+            max_memory,
+            force,
+        );
+        // The client may be gone already, and there is no one else to tell
+        let _ = stream.write_all(format!("{reply}\n").as_bytes());
+    }
+
+    unreachable!("incoming() never ends");
+}
+
+// A server that was killed leaves its socket behind, which would keep the next one from binding.
+// Only a socket that no one listens on is removed, so a running server isn't cut off from its
+// clients and a file given by mistake is left alone
+fn remove_stale_socket(socket: &Path) {
+    let Ok(metadata) = fs::symlink_metadata(socket) else {
+        return;
+    };
+    if !metadata.file_type().is_socket() {
+        fail(
+            EXIT_IO,
+            format!("{} exists and is not a socket", socket.display()),
+        );
+    }
+    if UnixStream::connect(socket).is_ok() {
+        fail(
+            EXIT_IO,
+            format!("another server is listening on {}", socket.display()),
+        );
+    }
+    if let Err(e) = fs::remove_file(socket) {
+        fail(
+            EXIT_IO,
+            format!(
+                "failed to remove the stale socket {}: {}",
+                socket.display(),
+                e
+            ),
+        );
+    }
+    if verbose() {
+        info(format!("Removed the stale socket {}", socket.display()));
+    }
+}
+
+fn handle(
+    stream: &mut UnixStream,
+    requests: &mut HashMap<u32, VecDeque<Instant>>,
+    rate_limit: u32,
+    min_version: Option<u8>,
+    max_memory: u64,
+    force: bool,
+) -> String {
+    let uid = match socket_peercred(&*stream) {
+        Ok(cred) => cred.uid.as_raw(),
+        Err(e) => return format!("Error: can't identify the client: {e}"),
+    };
+    let now = Instant::now();
+    let recent = requests.entry(uid).or_default();
+    while recent
+        .front()
+        .is_some_and(|&at| now.duration_since(at) >= RATE_WINDOW)
+    {
+        recent.pop_front();
+    }
+    if recent.len() >= rate_limit as usize {
+        if verbose() {
+            info(format!("Rate limited a request from uid {uid}"));
+        }
+        return "RateLimited".to_string();
+    }
+    recent.push_back(now);
+
+    let request = stream
+        .set_read_timeout(Some(READ_TIMEOUT))
+        .and_then(|()| Ok((read_field(stream)?, read_field(stream)?)));
+    let (password, bundle) = match request {
+        Ok(request) => request,
+        Err(e) => return format!("Error: {e}"),
+    };
+    let reply = match check_bundle(&password, &bundle, None, min_version, max_memory, force) {
+        Ok(true) => "Match".to_string(),
+        Ok(false) => "Mismatch".to_string(),
+        Err(e) => format!("Error: {e}"),
+    };
+    if verbose() {
+        info(format!("Request from uid {uid}: {reply}"));
+    }
+
+    reply
+}
+
+fn read_field(stream: &mut UnixStream) -> io::Result<Zeroizing<Vec<u8>>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FIELD_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("fields can't be longer than {MAX_FIELD_LEN} bytes"),
+        ));
+    }
+    let mut field = Zeroizing::new(vec![0; len as usize]);
+    stream.read_exact(&mut field)?;
+
+    Ok(field)
+}
@@ -0,0 +1,361 @@
+/*
+* mkdf — password-based master key derivation and verification tool
+* Copyright (C) 2026 L. M. Oukaci
+*
+* Contact: ouka.lotfi@gmail.com
+*
+* This program is free software: you can redistribute it and/or modify
+* it under the terms of the GNU General Public License as published by
+* the Free Software Foundation, either version 3 of the License, or
+* (at your option) any later version.
+*
+* This program is distributed in the hope that it will be useful,
+* but WITHOUT ANY WARRANTY; without even the implied warranty of
+* MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+* GNU General Public License for more details.
+*
+* You should have received a copy of the GNU General Public License
+* along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Interactive front-end.
+//!
+//! A single form to enter the password, pick a preset and run a hash or a verification through the
+//! same functions as the command line. The password is never drawn, only whether one was entered.
+//! The buffers holding it are allocated once, so that typing doesn't leave copies behind, and they
+//! are wiped along with the results when the form is left.
+
+use crate::{
+    decode_salt, encode_salt, fail, hash_password, parse_phash_mk_params, parse_salt_arg,
+    validate_phash, validate_phc_salt, validate_salt_entropy, verify_password, DerivationParams,
+    HexCase, SaltEncoding, Stage, StageParams, EXIT_IO, EXIT_USAGE, MIN_N, SALT_LEN,
+};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+use zeroize::{Zeroize, Zeroizing};
+
+// In bytes; longer passwords are cut off rather than moved to a bigger buffer
+const MAX_PASSWORD_LEN: usize = 1024;
+// Memory of the DPK stage of each preset, in MiB; the first preset keeps the params of the flags
+const PRESETS_MIB: [Option<u64>; 4] = [None, Some(64), Some(256), Some(1024)];
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Puts the terminal back in its normal mode if the form is up, so that `fail` can exit cleanly
+pub fn restore() {
+    if ACTIVE.swap(false, Ordering::Relaxed) {
+        ratatui::restore();
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Mode,
+    Preset,
+    Password,
+    Confirm,
+    Salt1,
+    Salt2,
+    Salt3,
+    Phash,
+}
+
+struct Form {
+    field: Field,
+    verify: bool,
+    preset: usize,
+    password: Zeroizing<String>,
+    confirm: Zeroizing<String>,
+    salts: [String; 3],
+    phash: String,
+    result: Zeroizing<String>,
+}
+
+impl Form {
+    fn fields(&self) -> &'static [Field] {
+        if self.verify {
+            &[
+                Field::Mode,
+                Field::Preset,
+                Field::Password,
+                Field::Salt1,
+                Field::Salt2,
+                Field::Salt3,
+                Field::Phash,
+            ]
+        } else {
+            &[Field::Mode, Field::Preset, Field::Password, Field::Confirm]
+        }
+    }
+
+    fn step(&mut self, forward: bool) {
+        let fields = self.fields();
+        let i = fields.iter().position(|&f| f == self.field).unwrap_or(0);
+        let i = if forward {
+            (i + 1) % fields.len()
+        } else {
+            (i + fields.len() - 1) % fields.len()
+        };
+        self.field = fields[i];
+    }
+
+    fn text(&mut self) -> Option<&mut String> {
+        match self.field {
+            Field::Mode | Field::Preset => None,
+            Field::Password => Some(&mut self.password),
+            Field::Confirm => Some(&mut self.confirm),
+            Field::Salt1 => Some(&mut self.salts[0]),
+            Field::Salt2 => Some(&mut self.salts[1]),
+            Field::Salt3 => Some(&mut self.salts[2]),
+            Field::Phash => Some(&mut self.phash),
+        }
+    }
+}
+
+pub fn run(params: &DerivationParams, encoding: SaltEncoding, case: HexCase, force: bool) {
+    if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+        fail(EXIT_USAGE, "mkdf tui is interactive and needs a terminal");
+    }
+    let secret = || Zeroizing::new(String::with_capacity(MAX_PASSWORD_LEN));
+    let mut form = Form {
+        field: Field::Password,
+        verify: false,
+        preset: 0,
+        password: secret(),
+        confirm: secret(),
+        salts: Default::default(),
+        phash: String::new(),
+        result: Zeroizing::new(String::new()),
+    };
+
+    let mut terminal = ratatui::try_init()
+        .unwrap_or_else(|e| fail(EXIT_IO, format!("failed to set up the terminal: {e}")));
+    ACTIVE.store(true, Ordering::Relaxed);
+    let result = event_loop(&mut terminal, &mut form, params, encoding, case, force);
+    restore();
+    drop(form);
+    if let Err(e) = result {
+        fail(EXIT_IO, format!("terminal error: {e}"));
+    }
+}
+
+fn event_loop(
+    terminal: &mut DefaultTerminal,
+    form: &mut Form,
+    params: &DerivationParams,
+    encoding: SaltEncoding,
+    case: HexCase,
+    force: bool,
+) -> io::Result<()> {
+    loop {
+        terminal.draw(|frame| draw(frame, form, params))?;
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match key.code {
+            KeyCode::Esc => return Ok(()),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(()),
+            KeyCode::Tab | KeyCode::Down => form.step(true),
+            KeyCode::BackTab | KeyCode::Up => form.step(false),
+            KeyCode::Left | KeyCode::Right | KeyCode::Char(' ') if form.field == Field::Mode => {
+                form.verify = !form.verify;
+            }
+            KeyCode::Left if form.field == Field::Preset => {
+                form.preset = (form.preset + PRESETS_MIB.len() - 1) % PRESETS_MIB.len();
+            }
+            KeyCode::Right | KeyCode::Char(' ') if form.field == Field::Preset => {
+                form.preset = (form.preset + 1) % PRESETS_MIB.len();
+            }
+            KeyCode::Enter => {
+                form.result = Zeroizing::new("Working...".to_string());
+                terminal.draw(|frame| draw(frame, form, params))?;
+                let params = preset_params(params, form.preset);
+                form.result = Zeroizing::new(match submit(form, &params, encoding, case, force) {
+                    Ok(result) | Err(result) => result,
+                });
+            }
+            KeyCode::Backspace => {
+                if let Some(text) = form.text() {
+                    text.pop();
+                }
+            }
+            KeyCode::Char(c) => {
+                if let Some(text) = form.text() {
+                    // The password buffers never grow, which would leave old copies unwiped
+                    if text.len() + c.len_utf8() <= text.capacity().max(MAX_PASSWORD_LEN) {
+                        text.push(c);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn preset_params(params: &DerivationParams, preset: usize) -> DerivationParams {
+    let Some(mib) = PRESETS_MIB[preset] else {
+        return *params;
+    };
+    DerivationParams {
+        dpk: StageParams {
+            n: preset_n(mib, params.dpk.r),
+            ..params.dpk
+        },
+        ..*params
+    }
+}
+
+// The largest power of two N that keeps yescrypt's 128 * r * N bytes within the budget
+fn preset_n(mib: u64, r: u32) -> u64 {
+    let n = (mib << 20) / (128 * u64::from(r));
+    (1 << n.max(1).ilog2()).max(MIN_N)
+}
+
+fn submit(
+    form: &Form,
+    params: &DerivationParams,
+    encoding: SaltEncoding,
+    case: HexCase,
+    force: bool,
+) -> Result<String, String> {
+    if form.password.is_empty() {
+        return Err("Enter a password first.".to_string());
+    }
+    let password = form.password.as_bytes();
+    if !form.verify {
+        if form.password != form.confirm {
+            return Err("The passwords don't match.".to_string());
+        }
+        let mut output = hash_password(
+            password,
+            &[Stage::Digest, Stage::Dpk],
+            params,
+            None,
+            false,
+            false,
+        );
+        let salt = |salt: Option<[u8; SALT_LEN]>| {
+            salt.map_or(String::new(), |s| encode_salt(&s, encoding, case))
+        };
+        let result = format!(
+            "salt1  {}\nphash  {}\nsalt2  {}\ndpk    {}\nsalt3  {}",
+            encode_salt(&output.salt1, encoding, case),
+            output.phash.as_deref().unwrap_or_default(),
+            salt(output.salt2),
+            output.dpk.as_ref().map_or("", |dpk| dpk.as_str()),
+            salt(output.salt3),
+        );
+        output.dpk.zeroize();
+        return Ok(result);
+    }
+
+    let mut salts = [[0; SALT_LEN]; 3];
+    for (i, (salt, given)) in salts.iter_mut().zip(&form.salts).enumerate() {
+        *salt = parse_salt_arg(given)
+            .and_then(|given| decode_salt(&given, encoding))
+            .map_err(|e| format!("Salt {}: {e}", i + 1))?;
+    }
+    let [salt1, salt2, salt3] = &salts;
+    let phash = form.phash.trim();
+    if phash.is_empty() {
+        return Err("Enter the phash to verify against.".to_string());
+    }
+    // The same checks as on the command line, reported in the form rather than exiting
+    validate_phash(phash, params.digest)?;
+    let params = DerivationParams {
+        mk: parse_phash_mk_params(phash, &params.mk, force)?,
+        ..*params
+    };
+    validate_salt_entropy(&[salt1, salt2, salt3], force)?;
+    validate_phc_salt(phash, salt2)?;
+    let dpk = Zeroizing::new(verify_password(
+        password, salt1, salt2, salt3, phash, None, &params,
+    ));
+
+    Ok(match dpk.as_deref() {
+        Some(dpk) => format!("Match\ndpk    {dpk}"),
+        None => "Mismatch".to_string(),
+    })
+}
+
+fn draw(frame: &mut Frame, form: &Form, params: &DerivationParams) {
+    let fields = form.fields();
+    let [top, bottom, help] = Layout::vertical([
+        Constraint::Length(fields.len() as u16 + 2),
+        Constraint::Min(3),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let hidden = |text: &str| {
+        if text.is_empty() {
+            "(empty)"
+        } else {
+            "(entered, hidden)"
+        }
+    };
+    let lines: Vec<Line> = fields
+        .iter()
+        .map(|&field| {
+            let (label, value) = match field {
+                Field::Mode => (
+                    "Mode",
+                    if form.verify {
+                        "< verify >"
+                    } else {
+                        "< hash >"
+                    }
+                    .to_string(),
+                ),
+                Field::Preset => (
+                    "Preset",
+                    format!("< {} >", preset_label(params, form.preset)),
+                ),
+                Field::Password => ("Password", hidden(&form.password).to_string()),
+                Field::Confirm => ("Confirm", hidden(&form.confirm).to_string()),
+                Field::Salt1 => ("Salt 1", form.salts[0].clone()),
+                Field::Salt2 => ("Salt 2", form.salts[1].clone()),
+                Field::Salt3 => ("Salt 3", form.salts[2].clone()),
+                Field::Phash => ("Phash", form.phash.clone()),
+            };
+            let line = Line::from(format!(" {label:<9} {value}"));
+            if field == form.field {
+                line.style(Style::new().add_modifier(Modifier::REVERSED))
+            } else {
+                line
+            }
+        })
+        .collect();
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title(" MKDF ")),
+        top,
+    );
+    frame.render_widget(
+        Paragraph::new(form.result.as_str())
+            .wrap(Wrap { trim: false })
+            .block(Block::bordered().title(" Result ")),
+        bottom,
+    );
+    frame.render_widget(
+        Line::from(" Tab/arrows: move   Left/Right: change   Enter: run   Esc: quit"),
+        help,
+    );
+}
+
+fn preset_label(params: &DerivationParams, preset: usize) -> String {
+    let dpk = preset_params(params, preset).dpk;
+    let mib = (128 * u64::from(dpk.r) * dpk.n) >> 20;
+    match PRESETS_MIB[preset] {
+        None => format!("command line: DPK N {}, r {} ({mib} MiB)", dpk.n, dpk.r),
+        Some(_) => format!("DPK N {}, r {} ({mib} MiB)", dpk.n, dpk.r),
+    }
+}